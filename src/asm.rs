@@ -0,0 +1,366 @@
+//! A small assembler producing Synacor program images.
+//!
+//! The syntax mirrors the disassembler's output:
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! start:  set r0 'A'          ; registers r0..r7, character literals
+//!         call print
+//!         out "done\n"        ; expands into one `out` per character
+//!         halt
+//! print:  out r0
+//!         ret
+//! table:  .data 1, 0x20, start
+//! name:   .string "synacor"
+//! ```
+//!
+//! Numbers are decimal or `0x` hex, operands may be separated by spaces or
+//! commas, and labels can be used anywhere a value is expected.
+
+use crate::opcode::Opcode;
+use crate::vm::{LIMIT, REGISTERS};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// A problem in the assembly source, with the 1-based line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+/// Splits a line into words and string literals, dropping any comment.
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => s.push(unescape(chars.next())?),
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c == '\'' {
+            chars.next();
+            let mut s = String::from("'");
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some('\\') => {
+                        s.push('\\');
+                        s.extend(chars.next());
+                    }
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated character literal".to_string()),
+                }
+            }
+            s.push('\'');
+            tokens.push(Token::Word(s));
+        } else {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == ';' {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(s));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn unescape(c: Option<char>) -> Result<char, String> {
+    match c {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('0') => Ok('\0'),
+        Some(c @ ('\\' | '\'' | '"')) => Ok(c),
+        Some(c) => Err(format!("unknown escape \\{}", c)),
+        None => Err("unterminated escape".to_string()),
+    }
+}
+
+/// A value to emit, resolved once all labels are known.
+#[derive(Debug, Clone)]
+enum Value {
+    Word(u16),
+    Label(String),
+}
+
+pub(crate) fn parse_number(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u16>().ok(),
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some(lit) = s.strip_prefix('\'') {
+        let lit = lit
+            .strip_suffix('\'')
+            .ok_or(format!("bad character literal {}", s))?;
+        let mut chars = lit.chars();
+        let c = match chars.next() {
+            Some('\\') => unescape(chars.next())?,
+            Some(c) => c,
+            None => return Err("empty character literal".to_string()),
+        };
+        if chars.next().is_some() {
+            return Err(format!("bad character literal {}", s));
+        }
+        return Ok(Value::Word(c as u16));
+    }
+
+    if let Some(reg) = s.strip_prefix('r') {
+        if let Ok(n) = reg.parse::<u16>() {
+            if (n as usize) < REGISTERS {
+                return Ok(Value::Word(LIMIT + n));
+            }
+            return Err(format!("invalid register {}", s));
+        }
+    }
+
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_number(s)
+            .map(Value::Word)
+            .ok_or(format!("invalid number {}", s));
+    }
+
+    if is_label(s) {
+        return Ok(Value::Label(s.to_string()));
+    }
+
+    Err(format!("invalid operand {}", s))
+}
+
+/// Parses one line into the values it emits, defining any leading labels.
+fn parse_line(
+    tokens: &[Token],
+    addr: usize,
+    labels: &mut HashMap<String, usize>,
+) -> Result<Vec<Value>, String> {
+    let mut tokens = tokens;
+    while let Some(Token::Word(w)) = tokens.first() {
+        match w.strip_suffix(':') {
+            Some(name) if is_label(name) => {
+                if labels.insert(name.to_string(), addr).is_some() {
+                    return Err(format!("duplicate label {}", name));
+                }
+                tokens = &tokens[1..];
+            }
+            _ => break,
+        }
+    }
+
+    let (head, args) = match tokens.split_first() {
+        Some((Token::Word(head), args)) => (head.as_str(), args),
+        Some((Token::Str(_), _)) => return Err("unexpected string".to_string()),
+        None => return Ok(vec![]),
+    };
+
+    let mut values = vec![];
+    match head {
+        ".data" => {
+            for arg in args {
+                match arg {
+                    Token::Word(w) => values.push(parse_value(w)?),
+                    Token::Str(_) => return Err("use .string for strings".to_string()),
+                }
+            }
+        }
+        ".string" => match args {
+            [Token::Str(s)] => values.extend(s.chars().map(|c| Value::Word(c as u16))),
+            _ => return Err(".string takes a single string".to_string()),
+        },
+        _ => {
+            let opcode = Opcode::from_name(head).ok_or(format!("unknown mnemonic {}", head))?;
+
+            // out "text" is shorthand for one out per character
+            if let (Opcode::Out, [Token::Str(s)]) = (opcode, args) {
+                for c in s.chars() {
+                    values.push(Value::Word(opcode.code()));
+                    values.push(Value::Word(c as u16));
+                }
+                return Ok(values);
+            }
+
+            if args.len() != opcode.arity() {
+                return Err(format!(
+                    "{} takes {} operands, got {}",
+                    head,
+                    opcode.arity(),
+                    args.len()
+                ));
+            }
+            values.push(Value::Word(opcode.code()));
+            for arg in args {
+                match arg {
+                    Token::Word(w) => match parse_value(w)? {
+                        Value::Word(val) if val >= LIMIT + REGISTERS as u16 => {
+                            return Err(format!("operand {} is neither a value nor a register", w))
+                        }
+                        value => values.push(value),
+                    },
+                    Token::Str(_) => return Err(format!("unexpected string for {}", head)),
+                }
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+/// Assembles `src` into an image meant to be loaded at `origin`; labels
+/// resolve to absolute addresses.
+pub fn assemble_at(src: &str, origin: usize) -> Result<Vec<u16>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut lines = vec![];
+    let mut addr = origin;
+
+    for (idx, line) in src.lines().enumerate() {
+        let err = |message| AsmError {
+            line: idx + 1,
+            message,
+        };
+        let tokens = tokenize(line).map_err(err)?;
+        let values = parse_line(&tokens, addr, &mut labels).map_err(err)?;
+        addr += values.len();
+        lines.push((idx + 1, values));
+    }
+
+    let mut image = vec![];
+    for (line, values) in lines {
+        for value in values {
+            match value {
+                Value::Word(w) => image.push(w),
+                Value::Label(name) => match labels.get(&name) {
+                    Some(addr) if *addr < LIMIT as usize => image.push(*addr as u16),
+                    Some(addr) => {
+                        return Err(AsmError {
+                            line,
+                            message: format!("label {} at {:#x} is out of memory", name, addr),
+                        })
+                    }
+                    None => {
+                        return Err(AsmError {
+                            line,
+                            message: format!("undefined label {}", name),
+                        })
+                    }
+                },
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+/// Assembles `src` into an image loaded at address 0.
+pub fn assemble(src: &str) -> Result<Vec<u16>, AsmError> {
+    assemble_at(src, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simple() {
+        assert_eq!(
+            assemble("add r0, r1, 4\nout r0 ; print it\n").unwrap(),
+            vec![9, 32768, 32769, 4, 19, 32768]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_directives() {
+        let src = "
+start:  set r0 'A'
+        call print
+        out \"ok\\n\"
+        jmp start
+print:  out r0
+        ret
+table:  .data 1, 0x20, print
+        .string \"a;b\"
+";
+        assert_eq!(
+            assemble_at(src, 0x100).unwrap(),
+            vec![
+                1, 32768, 65, 17, 0x10d, 19, 'o' as u16, 19, 'k' as u16, 19, 10, 6, 0x100, 19,
+                32768, 18, 1, 0x20, 0x10d, 'a' as u16, ';' as u16, 'b' as u16,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        use crate::disasm::disassemble;
+        use crate::symbols::SymbolTable;
+
+        let program = vec![
+            9, 32768, 32769, 4, 19, 'h' as u16, 19, '"' as u16, 19, 32768, 7, 32769, 0, 0x7fff,
+        ];
+        let src: Vec<String> = disassemble(&program, &SymbolTable::new(), 0..program.len())
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(assemble(&src.join("\n")).unwrap(), program);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(assemble("nop").unwrap_err().message, "unknown mnemonic nop");
+        assert_eq!(assemble("halt\njmp nowhere").unwrap_err().line, 2);
+        assert!(assemble("set r8 1").is_err());
+        assert!(assemble("add r0 r1").is_err());
+
+        let err = assemble("halt\nset r0 40000").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.message,
+            "operand 40000 is neither a value nor a register"
+        );
+        assert_eq!(assemble("set 32775 1").unwrap(), vec![1, 32775, 1]);
+        assert_eq!(assemble(".data 40000").unwrap(), vec![40000]);
+
+        let err = assemble_at("jmp end\nend: halt", 0x7fff).unwrap_err();
+        assert_eq!(err.line, 1);
+        assert_eq!(err.message, "label end at 0x8001 is out of memory");
+    }
+}
@@ -0,0 +1,319 @@
+//! Control-flow graph recovery.
+//!
+//! Code is found by recursive descent from a set of entry points rather than
+//! by sweeping memory, so data mixed in with the code is left alone. Every
+//! literal `call` target is taken to start a function, and each function is
+//! split into basic blocks at jump targets and after `jmp`, `jt`, `jf`,
+//! `ret` and `halt`. A `call` doesn't end a block, since control comes back
+//! to the next instruction. Jumps and calls through a register can't be
+//! followed statically and are only noted.
+
+use crate::disasm::disassemble;
+use crate::opcode::{Instruction, Opcode};
+use crate::symbols::{default_name, SymbolTable};
+use crate::vm::LIMIT;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How control leaves a basic block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Runs on into the block at this address, which something jumps to.
+    Next(u16),
+    /// `jmp` to a literal address.
+    Jump(u16),
+    /// `jt` or `jf`: to `target` when taken, `None` if it's a register, and
+    /// on to `next` otherwise.
+    Branch {
+        target: Option<u16>,
+        next: u16,
+    },
+    Return,
+    Halt,
+    /// `jmp` through a register.
+    Indirect,
+    /// Runs into a word that doesn't decode or off the end of memory.
+    Invalid,
+}
+
+/// A straight run of instructions with a single entry and exit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    /// The address after the last instruction.
+    pub end: u16,
+    pub instructions: Vec<Instruction>,
+    pub exit: Exit,
+}
+
+impl Block {
+    /// Addresses of the blocks control can go to next.
+    pub fn successors(&self) -> Vec<u16> {
+        match self.exit {
+            Exit::Next(next) | Exit::Jump(next) => vec![next],
+            Exit::Branch { target, next } => target.into_iter().chain([next]).collect(),
+            Exit::Return | Exit::Halt | Exit::Indirect | Exit::Invalid => vec![],
+        }
+    }
+
+    /// Literal `call` targets in the block, in order.
+    pub fn calls(&self) -> impl Iterator<Item = u16> + '_ {
+        self.instructions
+            .iter()
+            .filter(|instr| instr.opcode == Opcode::Call && instr.operands[0] < LIMIT)
+            .map(|instr| instr.operands[0])
+    }
+}
+
+/// The blocks reachable from a function's entry without following calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub entry: u16,
+    pub blocks: BTreeMap<u16, Block>,
+}
+
+impl Function {
+    /// Literal `call` targets anywhere in the function, without repeats.
+    pub fn calls(&self) -> BTreeSet<u16> {
+        self.blocks.values().flat_map(|b| b.calls()).collect()
+    }
+}
+
+/// Every function found from the entry points, by entry address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cfg {
+    pub functions: BTreeMap<u16, Function>,
+}
+
+/// Recovers the functions reachable from `entries` in `mem`, following
+/// calls to find more.
+pub fn recover(mem: &[u16], entries: &[u16]) -> Cfg {
+    let mut cfg = Cfg::default();
+    let mut pending: Vec<u16> = entries.iter().rev().copied().collect();
+    while let Some(entry) = pending.pop() {
+        if cfg.functions.contains_key(&entry) {
+            continue;
+        }
+        let function = recover_function(mem, entry);
+        pending.extend(function.calls().into_iter().rev());
+        cfg.functions.insert(entry, function);
+    }
+    cfg
+}
+
+fn decode(mem: &[u16], addr: u16) -> Option<Instruction> {
+    Instruction::decode(mem, addr as usize).filter(|instr| instr.next() <= LIMIT as usize)
+}
+
+/// Whether an instruction ends its block.
+fn ends_block(instr: &Instruction) -> bool {
+    matches!(
+        instr.opcode,
+        Opcode::Jmp | Opcode::Jt | Opcode::Jf | Opcode::Ret | Opcode::Halt
+    )
+}
+
+fn recover_function(mem: &[u16], entry: u16) -> Function {
+    // find every reachable instruction, and the addresses blocks start at
+    let mut code: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut leaders = BTreeSet::from([entry]);
+    let mut pending = vec![entry];
+    while let Some(addr) = pending.pop() {
+        if code.contains_key(&addr) {
+            continue;
+        }
+        let instr = match decode(mem, addr) {
+            Some(instr) => instr,
+            None => continue,
+        };
+        let next = instr.next() as u16;
+        let ops = &instr.operands;
+        let targets: Vec<u16> = match instr.opcode {
+            Opcode::Jmp => vec![ops[0]],
+            Opcode::Jt | Opcode::Jf => vec![ops[1], next],
+            Opcode::Ret | Opcode::Halt => vec![],
+            _ => vec![next],
+        };
+        if ends_block(&instr) {
+            leaders.extend(targets.iter().filter(|t| **t < LIMIT));
+        }
+        pending.extend(targets.into_iter().filter(|t| *t < LIMIT));
+        code.insert(addr, instr);
+    }
+
+    let mut blocks = BTreeMap::new();
+    for start in leaders.iter().copied() {
+        let mut instructions = vec![];
+        let mut addr = start;
+        let exit = loop {
+            let instr = match code.get(&addr) {
+                Some(instr) => instr.clone(),
+                None => break Exit::Invalid,
+            };
+            let next = instr.next() as u16;
+            let target = |val: u16| Some(val).filter(|t| *t < LIMIT);
+            let exit = match instr.opcode {
+                Opcode::Jmp => Some(target(instr.operands[0]).map_or(Exit::Indirect, Exit::Jump)),
+                Opcode::Jt | Opcode::Jf => Some(Exit::Branch {
+                    target: target(instr.operands[1]),
+                    next,
+                }),
+                Opcode::Ret => Some(Exit::Return),
+                Opcode::Halt => Some(Exit::Halt),
+                _ if leaders.contains(&next) => Some(Exit::Next(next)),
+                _ => None,
+            };
+            instructions.push(instr);
+            addr = next;
+            if let Some(exit) = exit {
+                break exit;
+            }
+        };
+        blocks.insert(
+            start,
+            Block {
+                start,
+                end: addr,
+                instructions,
+                exit,
+            },
+        );
+    }
+
+    Function { entry, blocks }
+}
+
+/// Escapes text for a double-quoted DOT string.
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Cfg {
+    /// The function with its entry at `addr`.
+    pub fn function(&self, addr: u16) -> Option<&Function> {
+        self.functions.get(&addr)
+    }
+
+    /// Graphviz DOT source for the function at `entry`, with instructions
+    /// from `mem` rendered as in a listing and names from `symbols`.
+    pub fn to_dot(&self, entry: u16, mem: &[u16], symbols: &SymbolTable) -> Option<String> {
+        let function = self.functions.get(&entry)?;
+        let name = |addr: u16| match symbols.get(addr) {
+            Some(name) => name.to_string(),
+            None => default_name(addr),
+        };
+
+        let mut out = format!("digraph \"{}\" {{\n", dot_escape(&name(entry)));
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for block in function.blocks.values() {
+            let mut label = match symbols.get(block.start) {
+                Some(name) => format!("{}:\\l", dot_escape(name)),
+                None if block.start == entry => format!("{}:\\l", dot_escape(&name(entry))),
+                None => String::new(),
+            };
+            let range = block.start as usize..block.end as usize;
+            for line in disassemble(mem, symbols, range) {
+                let text = format!("{:04x}  {}", line.addr, line.text);
+                label.push_str(&dot_escape(&text));
+                label.push_str("\\l");
+            }
+            match block.exit {
+                Exit::Indirect => label.push_str("(indirect jump)\\l"),
+                Exit::Invalid => label.push_str("(invalid code)\\l"),
+                _ => {}
+            }
+            out.push_str(&format!(
+                "    \"{:04x}\" [label=\"{}\"];\n",
+                block.start, label
+            ));
+        }
+
+        for block in function.blocks.values() {
+            let edge = |to: u16, label: Option<&str>| match label {
+                Some(label) => format!(
+                    "    \"{:04x}\" -> \"{:04x}\" [label=\"{}\"];\n",
+                    block.start, to, label
+                ),
+                None => format!("    \"{:04x}\" -> \"{:04x}\";\n", block.start, to),
+            };
+            match block.exit {
+                Exit::Next(to) | Exit::Jump(to) => out.push_str(&edge(to, None)),
+                Exit::Branch { target, next } => {
+                    let jt = block.instructions.last().map(|i| i.opcode) == Some(Opcode::Jt);
+                    let (taken, not_taken) = if jt {
+                        ("true", "false")
+                    } else {
+                        ("false", "true")
+                    };
+                    if let Some(target) = target {
+                        out.push_str(&edge(target, Some(taken)));
+                    }
+                    out.push_str(&edge(next, Some(not_taken)));
+                }
+                Exit::Return | Exit::Halt | Exit::Indirect | Exit::Invalid => {}
+            }
+        }
+        out.push_str("}\n");
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x000c, "double");
+        let r0 = LIMIT;
+        let mem = [
+            &[17, 0x000c][..], // 0000: call double
+            &[8, r0, 0x000b],  // 0002: jf r0 0x000b
+            &[9, r0, r0, 1],   // 0005: add r0 r0 1
+            &[6, 0x0002],      // 0009: jmp 0x0002
+            &[0],              // 000b: halt
+            &[9, r0, r0, r0],  // 000c: double: add r0 r0 r0
+            &[18],             // 0010: ret
+            &[0xffff],         // 0011: not code
+        ]
+        .concat();
+
+        let cfg = recover(&mem, &[0]);
+        assert_eq!(
+            cfg.functions.keys().copied().collect::<Vec<_>>(),
+            vec![0, 0x0c]
+        );
+
+        let main = cfg.function(0).unwrap();
+        let starts: Vec<u16> = main.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0, 2, 5, 0x0b]);
+        assert_eq!(main.blocks[&0].exit, Exit::Next(2));
+        assert_eq!(main.blocks[&0].calls().collect::<Vec<_>>(), vec![0x0c]);
+        assert_eq!(
+            main.blocks[&2].exit,
+            Exit::Branch {
+                target: Some(0x0b),
+                next: 5
+            }
+        );
+        assert_eq!(main.blocks[&5].successors(), vec![2]);
+        assert_eq!(main.blocks[&0x0b].exit, Exit::Halt);
+
+        let double = cfg.function(0x0c).unwrap();
+        assert_eq!(double.blocks.len(), 1);
+        assert_eq!(double.blocks[&0x0c].instructions.len(), 2);
+        assert_eq!(double.blocks[&0x0c].end, 0x11);
+
+        let dot = cfg.to_dot(0, &mem, &symbols).unwrap();
+        assert!(dot.starts_with("digraph \"sub_0000\" {\n"));
+        assert!(dot.contains("\"0000\" [label=\"sub_0000:\\l0000  call double\\l\"];"));
+        assert!(dot.contains("\"0002\" -> \"000b\" [label=\"false\"];"));
+        assert!(dot.contains("\"0002\" -> \"0005\" [label=\"true\"];"));
+        assert!(dot.contains("\"0005\" -> \"0002\";"));
+        assert!(cfg
+            .to_dot(0x0c, &mem, &symbols)
+            .unwrap()
+            .contains("double:\\l"));
+        assert_eq!(cfg.to_dot(5, &mem, &symbols), None);
+    }
+}
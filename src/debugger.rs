@@ -0,0 +1,701 @@
+//! Interactive debugging: the `.`-prefixed commands accepted at the game's
+//! input prompt, breakpoints, and the debugger prompt execution drops into
+//! when one is hit.
+
+use crate::disasm::format_instruction;
+use crate::error::VmError;
+use crate::opcode::{Instruction, Opcode};
+use crate::snapshot::Snapshot;
+use crate::trace::{TraceFilter, TraceFormat, Tracer};
+use crate::vm::{StopReason, LIMIT, REGISTERS, VM};
+
+/// Which kind of memory access a watchpoint reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn parse(s: &str) -> Option<Access> {
+        match s {
+            "r" => Some(Access::Read),
+            "w" => Some(Access::Write),
+            "rw" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Access::Read => "r",
+            Access::Write => "w",
+            Access::ReadWrite => "rw",
+        }
+    }
+}
+
+/// Watches the inclusive address range `start..=end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, access: Access) -> Watchpoint {
+        Watchpoint { start, end, access }
+    }
+
+    pub fn matches(&self, addr: u16, access: Access) -> bool {
+        (self.start..=self.end).contains(&addr)
+            && (self.access == Access::ReadWrite || self.access == access)
+    }
+}
+
+/// A memory access that triggered a watchpoint. For reads `old` and `new`
+/// are both the value read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub ip: u16,
+    pub addr: u16,
+    pub access: Access,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl VM {
+    /// Stops execution before the instruction at `addr` is executed.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Returns false if there was no breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops execution after any instruction that accesses watched memory
+    /// through `rmem`, `wmem` or a register store to a memory address.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes every watchpoint covering `addr`, returning how many there were.
+    pub fn remove_watchpoints(&mut self, addr: u16) -> usize {
+        let before = self.watchpoints.len();
+        self.watchpoints
+            .retain(|w| !(w.start..=w.end).contains(&addr));
+        before - self.watchpoints.len()
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Parses `start` or `start-end`.
+    fn parse_range(&self, s: &str) -> Option<(u16, u16)> {
+        TraceFilter::parse_range(s, &self.symbols)
+    }
+
+    /// If `val` looks like a return address pushed by `call`, describes the
+    /// call it returns from.
+    fn describe_return(&self, val: u16) -> Option<String> {
+        let call = (val as usize).checked_sub(Opcode::Call.size())?;
+        let instr = Instruction::decode(&self.mem[..LIMIT as usize], call)?;
+        if instr.opcode != Opcode::Call {
+            return None;
+        }
+        Some(format!(
+            "return to {} after {}",
+            self.symbols.symbolize(val),
+            format_instruction(&instr, &self.symbols)
+        ))
+    }
+
+    /// `addr` as an offset into the function starting at `func`.
+    fn in_function(&self, addr: u16, func: Option<u16>) -> String {
+        match func {
+            Some(func) => {
+                let name = match self.symbols.get(func) {
+                    Some(name) => name.to_string(),
+                    None => format!("{:04x}", func),
+                };
+                if addr == func {
+                    name
+                } else {
+                    format!("{}+0x{:x}", name, addr.wrapping_sub(func))
+                }
+            }
+            None => self.symbols.symbolize(addr),
+        }
+    }
+
+    /// One line per active call, innermost first, starting with the current
+    /// instruction.
+    pub fn backtrace(&self) -> Vec<String> {
+        let frames = &self.call_stack;
+        let mut lines = vec![];
+        let func = frames.last().map(|f| f.target);
+        lines.push(format!(
+            "#0  {:04x} in {}",
+            self.ip,
+            self.in_function(self.ip as u16, func)
+        ));
+        for (idx, frame) in frames.iter().enumerate().rev() {
+            let caller = idx.checked_sub(1).map(|i| frames[i].target);
+            lines.push(format!(
+                "#{:<2} {:04x} in {}",
+                frames.len() - idx,
+                frame.call_site,
+                self.in_function(frame.call_site, caller)
+            ));
+        }
+        lines
+    }
+
+    fn describe_ip(&self) -> String {
+        match Instruction::decode(&self.mem, self.ip) {
+            Some(instr) => format!(
+                "{}: {}",
+                self.describe_addr(self.ip as u16),
+                format_instruction(&instr, &self.symbols)
+            ),
+            None => self.describe_addr(self.ip as u16),
+        }
+    }
+
+    /// Hex and ASCII dump of `len` words, eight to a line.
+    fn dump_mem(&mut self, addr: u16, len: usize) -> Result<(), VmError> {
+        let end = (addr as usize).saturating_add(len).min(LIMIT as usize);
+        for start in (addr as usize..end).step_by(8) {
+            let words = &self.mem[start..(start + 8).min(end)];
+            let hex: Vec<String> = words.iter().map(|w| format!("{:04x}", w)).collect();
+            let ascii: String = words
+                .iter()
+                .map(|w| match *w {
+                    0x20..=0x7e => *w as u8 as char,
+                    _ => '.',
+                })
+                .collect();
+            let line = format!("DEBUG: {:04x}: {:<39}  {}", start, hex.join(" "), ascii);
+            self.debug_line(&line)?;
+        }
+        Ok(())
+    }
+
+    fn describe_watchpoint(&self, w: &Watchpoint) -> String {
+        if w.start == w.end {
+            format!("{} {}", self.describe_addr(w.start), w.access.name())
+        } else {
+            format!("{:04x}-{:04x} {}", w.start, w.end, w.access.name())
+        }
+    }
+
+    fn describe_watch_hit(&self, hit: &WatchHit) -> String {
+        let instr = match Instruction::decode(&self.mem, hit.ip as usize) {
+            Some(instr) => format_instruction(&instr, &self.symbols),
+            None => "???".to_string(),
+        };
+        match hit.access {
+            Access::Read => format!(
+                "DEBUG: watch {:04x} read by {}: {} (value {:04x})",
+                hit.addr,
+                self.describe_addr(hit.ip),
+                instr,
+                hit.old
+            ),
+            _ => format!(
+                "DEBUG: watch {:04x} written by {}: {} (old {:04x} new {:04x})",
+                hit.addr,
+                self.describe_addr(hit.ip),
+                instr,
+                hit.old,
+                hit.new
+            ),
+        }
+    }
+
+    /// Resolves a symbol name or a hex address.
+    fn parse_location(&self, s: &str) -> Option<u16> {
+        self.symbols.resolve(s)
+    }
+
+    /// `addr`, followed by its symbol if it has one.
+    fn describe_addr(&self, addr: u16) -> String {
+        match self.symbols.get(addr) {
+            Some(name) => format!("{:04x} ({})", addr, name),
+            None => format!("{:04x}", addr),
+        }
+    }
+
+    /// Writes a line of debugger feedback to the output device.
+    pub(crate) fn debug_line(&mut self, line: &str) -> Result<(), VmError> {
+        let res = self.output.write_str(&format!("{}\n", line));
+        res.map_err(|err| self.io_error(err))
+    }
+
+    /// Runs a `.`-prefixed debugger command. Returns true if the command asks
+    /// for execution to resume, which only matters at the debugger prompt.
+    pub(crate) fn handle_debug(&mut self, line: &str) -> Result<bool, VmError> {
+        let parts: &Vec<&str> = &line[1..].split(' ').collect();
+        let mut resume = false;
+        match parts[0] {
+            "wmem" => {
+                if parts.len() >= 3 {
+                    let addr = u16::from_str_radix(parts[1], 16);
+                    let val = u16::from_str_radix(parts[2], 16);
+
+                    if addr.as_ref().is_ok() && val.as_ref().is_ok() {
+                        let a = addr.unwrap();
+                        let v = val.unwrap();
+                        if a >= LIMIT {
+                            self.debug_line(&format!("DEBUG: invalid address: {:04x}", a))?;
+                        } else {
+                            self.debug_line(&format!("DEBUG: wmem {:04x} {:04x}", a, v))?;
+                            self.mem[a as usize] = v;
+                        }
+                    } else {
+                        self.debug_line("DEBUG: error parsing arguments for wmem")?;
+                    }
+                } else {
+                    self.debug_line("DEBUG: not enough arguments for wmem")?;
+                }
+            }
+            "wreg" => {
+                if parts.len() >= 3 {
+                    let reg = parts[1].parse::<u16>();
+                    let val = parts[2].parse::<u16>();
+
+                    if reg.as_ref().is_ok() && val.as_ref().is_ok() {
+                        let r = reg.unwrap();
+                        if r > 7 {
+                            self.debug_line(&format!("DEBUG: invalid register: {}", r))?;
+                        } else {
+                            let v = val.unwrap();
+                            self.debug_line(&format!("DEBUG: wreg {} {}", r, v))?;
+                            self.mem[r as usize + LIMIT as usize] = v;
+                        }
+                    } else {
+                        self.debug_line("DEBUG: error parsing arguments for wreg")?;
+                    }
+                } else {
+                    self.debug_line("DEBUG: not enough arguments for wreg")?;
+                }
+            }
+            "debug" => {
+                self.debug = !self.debug;
+                let msg = format!(
+                    "DEBUG: switched debug mode {}",
+                    if self.debug { "on " } else { "off" }
+                );
+                self.debug_line(&msg)?;
+            }
+            "save" => {
+                if parts.len() >= 2 {
+                    let msg = match self.snapshot().save(parts[1]) {
+                        Ok(()) => format!("DEBUG: saved state to {}", parts[1]),
+                        Err(err) => format!("DEBUG: could not save {}: {}", parts[1], err),
+                    };
+                    self.debug_line(&msg)?;
+                } else {
+                    self.debug_line("DEBUG: not enough arguments for save")?;
+                }
+            }
+            "load" => {
+                if parts.len() >= 2 {
+                    let msg = match Snapshot::load(parts[1]) {
+                        Ok(snapshot) => {
+                            self.restore(&snapshot);
+                            format!("DEBUG: loaded state from {}", parts[1])
+                        }
+                        Err(err) => format!("DEBUG: could not load {}: {}", parts[1], err),
+                    };
+                    self.debug_line(&msg)?;
+                } else {
+                    self.debug_line("DEBUG: not enough arguments for load")?;
+                }
+            }
+            "break" => {
+                if parts.len() >= 2 {
+                    match self.parse_location(parts[1]) {
+                        Some(addr) => {
+                            self.add_breakpoint(addr);
+                            let msg = format!("DEBUG: break at {}", self.describe_addr(addr));
+                            self.debug_line(&msg)?;
+                        }
+                        None => self.debug_line("DEBUG: error parsing arguments for break")?,
+                    }
+                } else {
+                    for addr in self.breakpoints.clone() {
+                        let msg = format!("DEBUG: break at {}", self.describe_addr(addr));
+                        self.debug_line(&msg)?;
+                    }
+                }
+            }
+            "delete" => {
+                if parts.len() >= 2 {
+                    match self.parse_location(parts[1]) {
+                        Some(addr) if self.remove_breakpoint(addr) => {
+                            let msg = format!("DEBUG: deleted {}", self.describe_addr(addr));
+                            self.debug_line(&msg)?;
+                        }
+                        Some(addr) => {
+                            self.debug_line(&format!("DEBUG: no breakpoint at {:04x}", addr))?
+                        }
+                        None => self.debug_line("DEBUG: error parsing arguments for delete")?,
+                    }
+                } else {
+                    self.breakpoints.clear();
+                    self.debug_line("DEBUG: deleted all breakpoints")?;
+                }
+            }
+            "watch" => {
+                if parts.len() >= 2 {
+                    let range = self.parse_range(parts[1]);
+                    let access = match parts.get(2) {
+                        Some(a) => Access::parse(a),
+                        None => Some(Access::ReadWrite),
+                    };
+                    match (range, access) {
+                        (Some((start, end)), Some(access)) => {
+                            let w = Watchpoint::new(start, end, access);
+                            self.add_watchpoint(w);
+                            let msg = format!("DEBUG: watch {}", self.describe_watchpoint(&w));
+                            self.debug_line(&msg)?;
+                        }
+                        _ => self.debug_line("DEBUG: error parsing arguments for watch")?,
+                    }
+                } else {
+                    for w in self.watchpoints.clone() {
+                        let msg = format!("DEBUG: watch {}", self.describe_watchpoint(&w));
+                        self.debug_line(&msg)?;
+                    }
+                }
+            }
+            "unwatch" => {
+                if parts.len() >= 2 {
+                    match self.parse_location(parts[1]) {
+                        Some(addr) => {
+                            let n = self.remove_watchpoints(addr);
+                            self.debug_line(&format!("DEBUG: deleted {} watchpoint(s)", n))?;
+                        }
+                        None => self.debug_line("DEBUG: error parsing arguments for unwatch")?,
+                    }
+                } else {
+                    self.watchpoints.clear();
+                    self.debug_line("DEBUG: deleted all watchpoints")?;
+                }
+            }
+            "regs" => {
+                let regs: Vec<String> = (0..REGISTERS as u16)
+                    .map(|r| format!("r{} {:04x}", r, self.regs(r)))
+                    .collect();
+                self.debug_line(&format!("DEBUG: {}", regs.join(" ")))?;
+            }
+            "ip" => {
+                let msg = format!("DEBUG: ip {}", self.describe_ip());
+                self.debug_line(&msg)?;
+            }
+            "stack" => match parts.get(1).map(|n| n.parse::<usize>()) {
+                Some(Err(_)) => self.debug_line("DEBUG: error parsing arguments for stack")?,
+                n => {
+                    let n = n.map_or(8, |n| n.unwrap());
+                    let depth = self.stack.len();
+                    let msg = format!(
+                        "DEBUG: stack has {} entries, top {} shown",
+                        depth,
+                        n.min(depth)
+                    );
+                    self.debug_line(&msg)?;
+                    for idx in (depth.saturating_sub(n)..depth).rev() {
+                        let val = self.stack[idx];
+                        let msg = match self.describe_return(val) {
+                            Some(ret) => format!("DEBUG: [{:>3}] {:04x}  {}", idx, val, ret),
+                            None => format!("DEBUG: [{:>3}] {:04x}", idx, val),
+                        };
+                        self.debug_line(&msg)?;
+                    }
+                }
+            },
+            "mem" => {
+                let addr = parts.get(1).and_then(|a| self.parse_location(a));
+                let len = match parts.get(2) {
+                    Some(len) => usize::from_str_radix(len, 16).ok(),
+                    None => Some(0x40),
+                };
+                match (addr, len) {
+                    (Some(addr), Some(len)) => self.dump_mem(addr, len)?,
+                    _ => self.debug_line("DEBUG: error parsing arguments for mem")?,
+                }
+            }
+            "bt" => {
+                for line in self.backtrace() {
+                    self.debug_line(&format!("DEBUG: {}", line))?;
+                }
+            }
+            "continue" => {
+                resume = true;
+            }
+            "step" => {
+                let n = match parts.get(1) {
+                    Some(n) => n.parse::<usize>().ok(),
+                    None => Some(1),
+                };
+                match n {
+                    Some(n) => {
+                        self.step_budget = Some(n);
+                        resume = true;
+                    }
+                    None => self.debug_line("DEBUG: error parsing arguments for step")?,
+                }
+            }
+            "stop" => {
+                let n = self.input_buffer.iter().filter(|c| **c == '\n').count();
+                self.input_buffer.clear();
+                self.at_line_start = true;
+                let msg = format!(
+                    "DEBUG: dropped {} queued lines, reading from the terminal",
+                    n
+                );
+                self.debug_line(&msg)?;
+            }
+            "history" => match parts.get(1) {
+                Some(&"off") => {
+                    self.disable_history();
+                    self.debug_line("DEBUG: history off")?;
+                }
+                Some(limit) => match limit.parse::<usize>() {
+                    Ok(limit) => {
+                        self.enable_history(limit);
+                        self.debug_line(&format!("DEBUG: recording up to {} instructions", limit))?;
+                    }
+                    Err(_) => self.debug_line("DEBUG: error parsing arguments for history")?,
+                },
+                None => {
+                    let msg = match self.history.as_ref() {
+                        Some(h) => format!(
+                            "DEBUG: history has {} of {} instructions",
+                            h.len(),
+                            h.limit()
+                        ),
+                        None => "DEBUG: history off".to_string(),
+                    };
+                    self.debug_line(&msg)?;
+                }
+            },
+            "rstep" | "rcontinue" => {
+                let n = match parts.get(1) {
+                    Some(n) if parts[0] == "rstep" => n.parse::<usize>().ok(),
+                    _ => Some(1),
+                };
+                match n {
+                    _ if self.history.is_none() => {
+                        self.debug_line("DEBUG: history off, enable it with .history <limit>")?
+                    }
+                    Some(n) => {
+                        let mut steps = 0;
+                        while (parts[0] == "rcontinue" || steps < n) && self.step_back() {
+                            steps += 1;
+                            if parts[0] == "rcontinue"
+                                && self.breakpoints.contains(&(self.ip as u16))
+                            {
+                                break;
+                            }
+                        }
+                        let msg = format!("DEBUG: stepped back {} instructions", steps);
+                        self.debug_line(&msg)?;
+                        if steps > 0 {
+                            // stop straight away so the prompt shows where we ended up
+                            self.step_budget = Some(0);
+                            resume = true;
+                        }
+                    }
+                    None => self
+                        .debug_line(&format!("DEBUG: error parsing arguments for {}", parts[0]))?,
+                }
+            }
+            "trace" => match parts.get(1) {
+                None => {
+                    let msg = if self.tracing() {
+                        "DEBUG: tracing on"
+                    } else {
+                        "DEBUG: tracing off"
+                    };
+                    self.debug_line(msg)?;
+                }
+                Some(&"off") => {
+                    let res = self.stop_trace();
+                    res.map_err(|err| self.io_error(err))?;
+                    self.debug_line("DEBUG: tracing off")?;
+                }
+                Some(path) => {
+                    let mut format = TraceFormat::Json;
+                    let mut filter = TraceFilter::default();
+                    let mut ok = true;
+                    for arg in &parts[2..] {
+                        if let Some(range) = arg.strip_prefix("range=") {
+                            filter.range = self.parse_range(range);
+                            ok &= filter.range.is_some();
+                        } else if let Some(names) = arg.strip_prefix("sym=") {
+                            filter
+                                .symbols
+                                .extend(names.split(',').map(|s| s.to_string()));
+                        } else {
+                            match *arg {
+                                "json" => format = TraceFormat::Json,
+                                "bin" => format = TraceFormat::Binary,
+                                _ => ok = false,
+                            }
+                        }
+                    }
+
+                    if !ok {
+                        self.debug_line("DEBUG: error parsing arguments for trace")?;
+                    } else {
+                        match Tracer::create(path, format, filter) {
+                            Ok(tracer) => {
+                                self.start_trace(tracer);
+                                self.debug_line(&format!("DEBUG: tracing to {}", path))?;
+                            }
+                            Err(err) => self.debug_line(&format!(
+                                "DEBUG: could not create {}: {}",
+                                path, err
+                            ))?,
+                        }
+                    }
+                }
+            },
+            "lastwrite" => {
+                let addr = parts.get(1).and_then(|a| match a.strip_prefix('r') {
+                    Some(r) => r
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|r| (*r as usize) < REGISTERS)
+                        .map(|r| LIMIT + r),
+                    None => self.parse_location(a),
+                });
+                match addr {
+                    Some(addr) => {
+                        let msg = match self.last_write(addr) {
+                            Some((ip, old)) => {
+                                let instr = match Instruction::decode(&self.mem, ip) {
+                                    Some(instr) => format_instruction(&instr, &self.symbols),
+                                    None => "???".to_string(),
+                                };
+                                format!(
+                                    "DEBUG: {:04x} last written by {}: {} (old {:04x})",
+                                    addr,
+                                    self.describe_addr(ip as u16),
+                                    instr,
+                                    old
+                                )
+                            }
+                            None => format!("DEBUG: no recorded write to {:04x}", addr),
+                        };
+                        self.debug_line(&msg)?;
+                    }
+                    None => self.debug_line("DEBUG: error parsing arguments for lastwrite")?,
+                }
+            }
+            _ => {
+                self.debug_line(&format!("DEBUG: unknown command: {}", parts[0]))?;
+            }
+        }
+        self.debug_line("")?;
+        Ok(resume)
+    }
+
+    /// Reads debugger commands from the input device until one of them
+    /// resumes execution. The leading `.` is optional at this prompt. Returns
+    /// false if the input ran out.
+    pub fn debug_prompt(&mut self) -> Result<bool, VmError> {
+        let msg = format!("DEBUG: stopped at {}", self.describe_ip());
+        self.debug_line(&msg)?;
+
+        loop {
+            let line = self
+                .output
+                .write_str("dbg> ")
+                .and_then(|_| self.output.flush())
+                .and_then(|_| self.input.read_line());
+            let line = match line {
+                Ok(Some(line)) => line,
+                Ok(None) => return Ok(false),
+                Err(err) => return Err(self.io_error(err)),
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let cmd = if line.starts_with('.') {
+                line.to_string()
+            } else {
+                format!(".{}", line)
+            };
+            if self.handle_debug(&cmd)? {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Runs like [`VM::run`], but drops into [`VM::debug_prompt`] whenever a
+    /// breakpoint or watchpoint is hit or a `.step` completes.
+    pub fn run_interactive(&mut self) -> Result<StopReason, VmError> {
+        loop {
+            let reason = self.run()?;
+            match reason {
+                StopReason::Breakpoint(_) | StopReason::Stepped => {}
+                StopReason::Watchpoint(hit) => {
+                    let msg = self.describe_watch_hit(&hit);
+                    self.debug_line(&msg)?;
+                }
+                _ => return Ok(reason),
+            }
+            if !self.debug_prompt()? {
+                return Ok(reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::SymbolTable;
+    use crate::vm::{test_vm_with_symbols, StopReason};
+
+    #[test]
+    fn test_inspection_commands() {
+        // call 0x0004; halt; in r0; ret
+        let program = vec![17, 0x0004, 0, 0, 20, 32768, 18];
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x0004, "read");
+        let (mut vm, output) = test_vm_with_symbols(
+            &program,
+            &symbols,
+            ".regs\n.ip\n.stack\n.mem 0 8\n.mem 7fff ffffffffffffffff\n",
+        );
+
+        assert_eq!(vm.run(), Ok(StopReason::EndOfInput));
+        let expected = "DEBUG: r0 0000 r1 0000 r2 0000 r3 0000 r4 0000 r5 0000 r6 0000 r7 0000
+
+DEBUG: ip 0004 (read): in r0
+
+DEBUG: stack has 1 entries, top 1 shown
+DEBUG: [  0] 0002  return to 0002 after call read
+
+DEBUG: 0000: 0011 0004 0000 0000 0014 8000 0012 0000  ........
+
+DEBUG: 7fff: 0000                                     .
+
+";
+        assert_eq!(output.contents(), expected);
+    }
+}
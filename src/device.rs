@@ -0,0 +1,166 @@
+//! Input and output devices the VM uses for the `in` and `out` instructions.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+/// A source of input lines for the `in` instruction.
+pub trait Input {
+    /// Reads the next line without its trailing newline, or `None` once the
+    /// input is exhausted.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+}
+
+/// A sink for characters written by the `out` instruction and debug messages.
+pub trait Output {
+    fn write_str(&mut self, s: &str) -> io::Result<()>;
+
+    /// Called before the VM blocks on input.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn strip_newline(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    line
+}
+
+/// Reads lines from the terminal.
+#[derive(Debug, Default)]
+pub struct StdinInput;
+
+impl Input for StdinInput {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(strip_newline(line)))
+    }
+}
+
+/// Writes to the terminal.
+#[derive(Debug, Default)]
+pub struct StdoutOutput;
+
+impl Output for StdoutOutput {
+    fn write_str(&mut self, s: &str) -> io::Result<()> {
+        io::stdout().lock().write_all(s.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().lock().flush()
+    }
+}
+
+/// Scripted input held in memory.
+#[derive(Debug, Default, Clone)]
+pub struct BufferInput {
+    lines: VecDeque<String>,
+}
+
+impl BufferInput {
+    /// Creates an input that yields each line of `text` in turn.
+    pub fn new(text: &str) -> BufferInput {
+        BufferInput {
+            lines: text.lines().map(|l| l.to_string()).collect(),
+        }
+    }
+
+    pub fn push_line(&mut self, line: &str) {
+        self.lines.push_back(line.to_string());
+    }
+}
+
+impl Input for BufferInput {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        Ok(self.lines.pop_front())
+    }
+}
+
+/// Captures output in memory. Clones share the same buffer, so keep a clone
+/// around to inspect what the VM wrote.
+#[derive(Debug, Default, Clone)]
+pub struct BufferOutput {
+    buffer: Rc<RefCell<String>>,
+}
+
+impl BufferOutput {
+    pub fn new() -> BufferOutput {
+        BufferOutput::default()
+    }
+
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        self.buffer.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.buffer.borrow_mut().clear();
+    }
+}
+
+impl Output for BufferOutput {
+    fn write_str(&mut self, s: &str) -> io::Result<()> {
+        self.buffer.borrow_mut().push_str(s);
+        Ok(())
+    }
+}
+
+/// Reads input lines from a file.
+#[derive(Debug)]
+pub struct FileInput {
+    reader: BufReader<File>,
+}
+
+impl FileInput {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileInput> {
+        Ok(FileInput {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+}
+
+impl Input for FileInput {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(strip_newline(line)))
+    }
+}
+
+/// Writes output to a file, replacing any previous contents.
+#[derive(Debug)]
+pub struct FileOutput {
+    writer: BufWriter<File>,
+}
+
+impl FileOutput {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileOutput> {
+        Ok(FileOutput {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl Output for FileOutput {
+    fn write_str(&mut self, s: &str) -> io::Result<()> {
+        self.writer.write_all(s.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
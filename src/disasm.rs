@@ -0,0 +1,176 @@
+//! Static disassembly of program images.
+//!
+//! The listing uses the same mnemonics as the VM's debug trace, `r0`..`r7` for
+//! registers, hex for literals and symbol names for jump and call targets.
+//! Runs of `out` with literal characters are folded into a single
+//! `out "..."` line, and words that do not decode are emitted as `.data`.
+
+use crate::opcode::{Instruction, Opcode};
+use crate::symbols::SymbolTable;
+use crate::vm::{LIMIT, REGISTERS};
+use std::ops::Range;
+
+/// Renders an operand as a register name or a hex literal.
+pub fn format_operand(val: u16) -> String {
+    if (LIMIT..LIMIT + REGISTERS as u16).contains(&val) {
+        format!("r{}", val - LIMIT)
+    } else {
+        format!("0x{:04x}", val)
+    }
+}
+
+fn format_target(val: u16, symbols: &SymbolTable) -> String {
+    match symbols.get(val) {
+        Some(name) if val < LIMIT => name.to_string(),
+        _ => format_operand(val),
+    }
+}
+
+fn escape(c: char) -> Option<String> {
+    match c {
+        '\n' => Some("\\n".to_string()),
+        '\t' => Some("\\t".to_string()),
+        '\\' => Some("\\\\".to_string()),
+        ' '..='~' => Some(c.to_string()),
+        _ => None,
+    }
+}
+
+/// Renders an `out` operand as a character literal where possible.
+fn format_char(val: u16) -> String {
+    let c = char::from_u32(val as u32).unwrap_or('\0');
+    match escape(c) {
+        Some(_) if c == '\'' => "'\\''".to_string(),
+        Some(s) => format!("'{}'", s),
+        None => format_operand(val),
+    }
+}
+
+/// Renders a single decoded instruction.
+pub fn format_instruction(instr: &Instruction, symbols: &SymbolTable) -> String {
+    let ops = &instr.operands;
+    let args: Vec<String> = match instr.opcode {
+        Opcode::Jmp | Opcode::Call => vec![format_target(ops[0], symbols)],
+        Opcode::Jt | Opcode::Jf => vec![format_operand(ops[0]), format_target(ops[1], symbols)],
+        Opcode::Out if ops[0] < LIMIT => vec![format_char(ops[0])],
+        _ => ops.iter().map(|op| format_operand(*op)).collect(),
+    };
+
+    if args.is_empty() {
+        instr.opcode.name().to_string()
+    } else {
+        format!("{} {}", instr.opcode.name(), args.join(" "))
+    }
+}
+
+/// A printable `out` with a literal operand, as part of a run.
+fn out_char(mem: &[u16], addr: usize) -> Option<char> {
+    let instr = Instruction::decode(mem, addr)?;
+    if instr.opcode != Opcode::Out {
+        return None;
+    }
+    let c = char::from_u32(instr.operands[0] as u32)?;
+    escape(c).map(|_| c)
+}
+
+/// One line of a listing, covering `size` words starting at `addr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: usize,
+    pub size: usize,
+    pub text: String,
+}
+
+/// Disassembles `range` of `mem` by linear sweep.
+pub fn disassemble(mem: &[u16], symbols: &SymbolTable, range: Range<usize>) -> Vec<Line> {
+    let end = range.end.min(mem.len());
+    let mut lines = vec![];
+    let mut addr = range.start;
+
+    while addr < end {
+        // fold runs of `out` into a string, stopping at labels so they stay visible
+        let mut run = String::new();
+        let mut next = addr;
+        while next < end && (next == addr || symbols.get(next as u16).is_none()) {
+            match out_char(mem, next) {
+                Some(c) => run.push(c),
+                None => break,
+            }
+            next += Opcode::Out.size();
+        }
+        if run.chars().count() > 1 {
+            let text: String = run.chars().map(|c| escape(c).unwrap()).collect();
+            let text = text.replace('"', "\\\"");
+            lines.push(Line {
+                addr,
+                size: next - addr,
+                text: format!("out \"{}\"", text),
+            });
+            addr = next;
+            continue;
+        }
+
+        match Instruction::decode(mem, addr) {
+            Some(instr) if instr.next() <= end => {
+                lines.push(Line {
+                    addr,
+                    size: instr.opcode.size(),
+                    text: format_instruction(&instr, symbols),
+                });
+                addr = instr.next();
+            }
+            _ => {
+                lines.push(Line {
+                    addr,
+                    size: 1,
+                    text: format!(".data 0x{:04x}", mem[addr]),
+                });
+                addr += 1;
+            }
+        }
+    }
+
+    lines
+}
+
+/// Produces a full listing with addresses and symbol labels.
+pub fn listing(mem: &[u16], symbols: &SymbolTable, range: Range<usize>) -> String {
+    let mut out = String::new();
+    for line in disassemble(mem, symbols, range) {
+        if let Some(name) = symbols.get(line.addr as u16) {
+            out.push_str(&format!("\n{}:\n", name));
+        }
+        out.push_str(&format!("  {:04x}  {}\n", line.addr, line.text));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x000b, "print");
+        // add r0 r1 4; call print; out "hi"; halt
+        // print: out r0; ret
+        // followed by a word that is not an instruction
+        let mem = vec![
+            9, 32768, 32769, 4, 17, 0x000b, 19, 'h' as u16, 19, 'i' as u16, 0, 19, 32768, 18,
+            0x7fff,
+        ];
+
+        let expected = "  0000  add r0 r1 0x0004
+  0004  call print
+  0006  out \"hi\"
+  000a  halt
+
+print:
+  000b  out r0
+  000d  ret
+  000e  .data 0x7fff
+";
+        assert_eq!(listing(&mem, &symbols, 0..mem.len()), expected);
+    }
+}
@@ -0,0 +1,119 @@
+//! Finding the program's functions, to fill in a symbol table.
+//!
+//! Every `call` target is taken to be a function. Literal targets are found
+//! statically with [`crate::cfg::recover`], which only reaches code it can
+//! follow from its entry points. Much of `challenge.bin` is only reached
+//! through calls via a register, so [`run_calls`] also watches a run of the
+//! program for the targets it actually calls.
+
+use crate::cfg;
+use crate::error::VmError;
+use crate::opcode::Opcode;
+use crate::symbols::{default_name, SymbolTable};
+use crate::vm::{StopReason, LIMIT, REGISTERS, VM};
+use std::collections::BTreeSet;
+
+/// A budget for [`run_calls`], about ten times what playing through all of
+/// `challenge.bin` takes.
+pub const RUN_BUDGET: usize = 10_000_000;
+
+/// Literal `call` targets in the code reachable from `entries`.
+pub fn static_calls(mem: &[u16], entries: &[u16]) -> BTreeSet<u16> {
+    cfg::recover(mem, entries)
+        .functions
+        .values()
+        .flat_map(|function| function.calls())
+        .collect()
+}
+
+/// Runs `vm` until it stops or has executed `budget` instructions, returning
+/// every address it called, including calls through a register, and why it
+/// stopped: [`StopReason::StepLimit`] if the budget ran out.
+pub fn run_calls(vm: &mut VM, budget: usize) -> Result<(BTreeSet<u16>, StopReason), VmError> {
+    let mut calls = BTreeSet::new();
+    let mut executed = 0;
+    let reason = vm.run_until(|vm| {
+        if executed == budget {
+            return true;
+        }
+        executed += 1;
+        let (ip, mem) = (vm.ip(), vm.mem());
+        if ip + 1 < LIMIT as usize && mem[ip] == Opcode::Call.code() {
+            let target = match mem[ip + 1] {
+                val if val < LIMIT => Some(val),
+                // anything past the registers faults when the call runs
+                val => (val < LIMIT + REGISTERS as u16).then(|| vm.regs(val - LIMIT)),
+            };
+            calls.extend(target);
+        }
+        false
+    })?;
+    match reason {
+        StopReason::Condition => Ok((calls, StopReason::StepLimit)),
+        reason => Ok((calls, reason)),
+    }
+}
+
+/// `symbols` with every address in `calls` it has no name for added under
+/// its [`default_name`].
+pub fn name_functions(symbols: &SymbolTable, calls: &BTreeSet<u16>) -> SymbolTable {
+    let mut named = symbols.clone();
+    for addr in calls {
+        if named.get(*addr).is_none() {
+            named.insert(*addr, &default_name(*addr));
+        }
+    }
+    named
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::test_vm;
+
+    #[test]
+    fn test_discover() {
+        let r0 = LIMIT;
+        let program = [
+            &[17, 0x000a][..], // 0000: call 0x000a
+            &[1, r0, 0x000b],  // 0002: set r0 0x000b
+            &[17, r0],         // 0005: call r0
+            &[0],              // 0007: halt
+            &[17, 0x000c],     // 0008: never runs
+            &[18],             // 000a: ret
+            &[18],             // 000b: ret
+            &[18],             // 000c: ret
+        ]
+        .concat();
+
+        let found = static_calls(&program, &[0]);
+        assert_eq!(found, BTreeSet::from([0x0a]));
+
+        let (mut vm, _) = test_vm(&program, "");
+        let (ran, reason) = run_calls(&mut vm, 100).unwrap();
+        assert_eq!(ran, BTreeSet::from([0x0a, 0x0b]));
+        assert_eq!(reason, StopReason::Halted);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x0a, "first");
+        let named = name_functions(&symbols, &found.union(&ran).copied().collect());
+        assert_eq!(
+            named.iter().collect::<Vec<_>>(),
+            vec![(0x0a, "first"), (0x0b, "sub_000b")]
+        );
+
+        // a call through something that isn't a register fails like a run
+        let (mut vm, _) = test_vm(&[17, LIMIT + 12], "");
+        assert!(matches!(
+            run_calls(&mut vm, 100),
+            Err(VmError::InvalidRegister { ip: 0, .. })
+        ));
+
+        // a program that never stops gives up with what it found:
+        // call 0x0004; jmp 0; ret
+        let (mut vm, _) = test_vm(&[17, 4, 6, 0, 18], "");
+        let (ran, reason) = run_calls(&mut vm, 1000).unwrap();
+        assert_eq!(ran, BTreeSet::from([4]));
+        assert_eq!(reason, StopReason::StepLimit);
+    }
+}
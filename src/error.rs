@@ -0,0 +1,133 @@
+//! Errors raised by the VM while executing a program.
+
+use crate::opcode::Opcode;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// A fault in the running program. Every variant records the address of the
+/// faulting instruction, and where it could be decoded, its opcode word and
+/// raw operands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The word at `ip` is not one of the 22 opcodes.
+    InvalidOpcode { ip: usize, opcode: u16 },
+    /// An operand was neither a literal (`0..32768`) nor a register (`32768..32776`).
+    InvalidRegister {
+        ip: usize,
+        opcode: u16,
+        operands: Vec<u16>,
+        value: u16,
+    },
+    /// A memory access, or the operands of the instruction itself, fell outside memory.
+    InvalidAddress {
+        ip: usize,
+        opcode: u16,
+        operands: Vec<u16>,
+        addr: usize,
+    },
+    /// `pop` was executed with an empty stack.
+    StackUnderflow {
+        ip: usize,
+        opcode: u16,
+        operands: Vec<u16>,
+    },
+    /// `mod` with a zero divisor.
+    DivisionByZero {
+        ip: usize,
+        opcode: u16,
+        operands: Vec<u16>,
+    },
+    /// Execution jumped or ran past the end of memory.
+    IpOutOfRange { ip: usize },
+    /// The input or output device failed.
+    Io {
+        ip: usize,
+        kind: io::ErrorKind,
+        message: String,
+    },
+}
+
+impl VmError {
+    /// Address of the instruction that faulted.
+    pub fn ip(&self) -> usize {
+        match self {
+            VmError::InvalidOpcode { ip, .. }
+            | VmError::InvalidRegister { ip, .. }
+            | VmError::InvalidAddress { ip, .. }
+            | VmError::StackUnderflow { ip, .. }
+            | VmError::DivisionByZero { ip, .. }
+            | VmError::IpOutOfRange { ip }
+            | VmError::Io { ip, .. } => *ip,
+        }
+    }
+}
+
+fn describe(opcode: u16, operands: &[u16]) -> String {
+    let name = Opcode::from_u16(opcode).map_or("???", |op| op.name());
+    let mut s = name.to_string();
+    for operand in operands {
+        s.push_str(&format!(" {:04x}", operand));
+    }
+    s
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidOpcode { ip, opcode } => {
+                write!(f, "{:04x}: invalid opcode {}", ip, opcode)
+            }
+            VmError::InvalidRegister {
+                ip,
+                opcode,
+                operands,
+                value,
+            } => write!(
+                f,
+                "{:04x}: {}: invalid register {}",
+                ip,
+                describe(*opcode, operands),
+                value
+            ),
+            VmError::InvalidAddress {
+                ip,
+                opcode,
+                operands,
+                addr,
+            } => write!(
+                f,
+                "{:04x}: {}: invalid address {:04x}",
+                ip,
+                describe(*opcode, operands),
+                addr
+            ),
+            VmError::StackUnderflow {
+                ip,
+                opcode,
+                operands,
+            } => write!(
+                f,
+                "{:04x}: {}: pop from empty stack",
+                ip,
+                describe(*opcode, operands)
+            ),
+            VmError::DivisionByZero {
+                ip,
+                opcode,
+                operands,
+            } => write!(
+                f,
+                "{:04x}: {}: division by zero",
+                ip,
+                describe(*opcode, operands)
+            ),
+            VmError::IpOutOfRange { ip } => {
+                write!(f, "ran outside of memory range at ip={}", ip)
+            }
+            VmError::Io { ip, message, .. } => write!(f, "{:04x}: i/o error: {}", ip, message),
+        }
+    }
+}
+
+impl Error for VmError {}
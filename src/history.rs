@@ -0,0 +1,239 @@
+//! Undo log for reverse execution.
+//!
+//! While history is enabled, every instruction records how to undo its effect
+//! on memory, registers, the stack, the call stack and queued input. Output
+//! that has already been written cannot be taken back.
+
+use crate::vm::{Frame, VM};
+use std::collections::VecDeque;
+
+/// One undoable effect of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// Memory or a register was overwritten.
+    Mem { addr: u16, old: u16 },
+    /// A value was pushed onto the stack.
+    Push,
+    /// A value was popped off the stack.
+    Pop(u16),
+    /// A frame was pushed onto the call stack.
+    FramePush,
+    /// A frame was dropped from the call stack.
+    FramePop(Frame),
+    /// A character of input was consumed.
+    Input { c: char, at_line_start: bool },
+    /// Marks the end of the instruction at `ip`; the changes before it, back
+    /// to the previous marker, belong to that instruction.
+    End(usize),
+}
+
+/// A bounded log of the most recently executed instructions.
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    changes: VecDeque<Change>,
+    instructions: usize,
+    limit: usize,
+}
+
+impl History {
+    pub(crate) fn new(limit: usize) -> History {
+        History {
+            changes: VecDeque::new(),
+            instructions: 0,
+            limit,
+        }
+    }
+
+    pub(crate) fn record(&mut self, change: Change) {
+        self.changes.push_back(change);
+    }
+
+    /// Forgets everything recorded so far, keeping the limit.
+    pub(crate) fn clear(&mut self) {
+        self.changes.clear();
+        self.instructions = 0;
+    }
+
+    /// Closes the record for the instruction at `ip`, forgetting the oldest
+    /// instruction if the log is full.
+    pub(crate) fn commit(&mut self, ip: usize) {
+        self.changes.push_back(Change::End(ip));
+        self.instructions += 1;
+        while self.instructions > self.limit {
+            while let Some(change) = self.changes.pop_front() {
+                if let Change::End(_) = change {
+                    break;
+                }
+            }
+            self.instructions -= 1;
+        }
+    }
+
+    /// Drops changes recorded by an instruction that did not complete.
+    pub(crate) fn discard_uncommitted(&mut self) {
+        while let Some(change) = self.changes.back() {
+            if let Change::End(_) = change {
+                break;
+            }
+            self.changes.pop_back();
+        }
+    }
+
+    /// Removes the newest instruction, returning its address and changes,
+    /// newest change first.
+    fn pop(&mut self) -> Option<(usize, Vec<Change>)> {
+        let ip = match self.changes.pop_back()? {
+            Change::End(ip) => ip,
+            _ => unreachable!("uncommitted changes in history"),
+        };
+        let mut changes = vec![];
+        while let Some(change) = self.changes.back() {
+            if let Change::End(_) = change {
+                break;
+            }
+            changes.push(self.changes.pop_back().unwrap());
+        }
+        self.instructions -= 1;
+        Some((ip, changes))
+    }
+
+    /// Number of instructions that can be undone.
+    pub(crate) fn len(&self) -> usize {
+        self.instructions
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The newest instruction that wrote `addr`, and the value it replaced.
+    pub(crate) fn last_write(&self, addr: u16) -> Option<(usize, u16)> {
+        let mut ip = None;
+        for change in self.changes.iter().rev() {
+            match change {
+                Change::End(end) => ip = Some(*end),
+                Change::Mem { addr: a, old } if *a == addr => return ip.map(|ip| (ip, *old)),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl VM {
+    /// Starts recording an undo log of the last `limit` instructions,
+    /// discarding any previous log.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// Number of instructions that can currently be stepped back over.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.len())
+    }
+
+    pub(crate) fn record(&mut self, change: Change) {
+        if let Some(history) = self.history.as_mut() {
+            history.record(change);
+        }
+    }
+
+    /// Undoes the most recently executed instruction. Returns false if there
+    /// is no history to undo. An instruction that faulted never completed,
+    /// so it isn't one to undo.
+    pub fn step_back(&mut self) -> bool {
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => return false,
+        };
+        history.discard_uncommitted();
+        let (ip, changes) = match history.pop() {
+            Some(record) => record,
+            None => return false,
+        };
+
+        for change in changes {
+            match change {
+                Change::Mem { addr, old } => self.mem[addr as usize] = old,
+                Change::Push => {
+                    self.stack.pop();
+                }
+                Change::Pop(val) => self.stack.push(val),
+                Change::FramePush => {
+                    self.call_stack.pop();
+                }
+                Change::FramePop(frame) => self.call_stack.push(frame),
+                Change::Input { c, at_line_start } => {
+                    self.input_buffer.push_front(c);
+                    self.at_line_start = at_line_start;
+                }
+                Change::End(_) => unreachable!("end marker inside a record"),
+            }
+        }
+
+        self.ip = ip;
+        // running forward again should execute this instruction, not stop on
+        // a breakpoint we just stepped back onto
+        self.resume_from = Some(ip);
+        true
+    }
+
+    /// The address of the newest recorded instruction that wrote `addr`, and
+    /// the value it overwrote.
+    pub fn last_write(&self, addr: u16) -> Option<(usize, u16)> {
+        self.history.as_ref()?.last_write(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::VmError;
+    use crate::vm::{test_vm, StopReason};
+
+    #[test]
+    fn test_step_back() {
+        // push 7; call 0x0006; halt; set r0 r1; pop r1; in r2; ret
+        let program = vec![
+            2, 7, 17, 0x0006, 0, 0, 1, 32768, 32769, 3, 32769, 20, 32770, 18,
+        ];
+        let (mut vm, _) = test_vm(&program, "x");
+        vm.set_reg(1, 3);
+        vm.enable_history(100);
+        let start = vm.snapshot();
+
+        vm.add_breakpoint(0x000d);
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint(0x000d)));
+        assert_eq!(vm.regs(2), 'x' as u16);
+        assert_eq!(vm.history_len(), 5);
+        assert_eq!(vm.last_write(32768), Some((0x0006, 0)));
+
+        while vm.step_back() {}
+        assert_eq!(vm.mem(), &start.mem[..]);
+        assert_eq!(vm.stack(), &start.stack[..]);
+        assert_eq!(vm.ip(), 0);
+        assert!(vm.call_stack().is_empty());
+
+        // the consumed input is queued again, so replaying gives the same result
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint(0x000d)));
+        assert_eq!(vm.regs(2), 'x' as u16);
+    }
+
+    #[test]
+    fn test_step_back_after_fault() {
+        // push 5; pop 40000
+        let (mut vm, _) = test_vm(&[2, 5, 3, 40000], "");
+        vm.enable_history(10);
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::InvalidRegister { ip: 2, .. })
+        ));
+        assert!(vm.step_back());
+        assert_eq!(vm.ip(), 0);
+        assert!(vm.stack().is_empty());
+        assert!(!vm.step_back());
+    }
+}
@@ -0,0 +1,103 @@
+//! Native implementations of routines in the program.
+//!
+//! A hook registered for an address runs in place of every `call` to it. It
+//! gets the registers at the call and leaves them as the routine would have
+//! on returning, and execution carries on after the `call` without anything
+//! being pushed. Hooks don't see memory or the stack, so they suit routines
+//! that compute a result from registers alone, such as the teleporter's
+//! confirmation.
+
+use crate::error::VmError;
+use crate::vm::{LIMIT, REGISTERS, VM};
+
+/// The registers as a hook sees them.
+pub type Registers = [u16; REGISTERS];
+
+/// Stands in for a routine. Returning false declines the call, leaving the
+/// registers untouched, and the routine runs in the VM as usual. Register
+/// values are stored modulo 32768.
+pub type Hook = Box<dyn FnMut(&mut Registers) -> bool>;
+
+impl VM {
+    /// Runs `hook` instead of the routine at `addr`, replacing any hook
+    /// already registered there.
+    pub fn add_hook(&mut self, addr: u16, hook: Hook) {
+        self.hooks.insert(addr, hook);
+    }
+
+    /// Returns false if no hook was registered at `addr`.
+    pub fn remove_hook(&mut self, addr: u16) -> bool {
+        self.hooks.remove(&addr).is_some()
+    }
+
+    /// Addresses with a hook registered, in order.
+    pub fn hooks(&self) -> impl Iterator<Item = u16> + '_ {
+        self.hooks.keys().copied()
+    }
+
+    /// Gives the hook for `target`, if any, the chance to handle a call.
+    /// Registers it changes are written back as if by the instruction.
+    pub(crate) fn run_hook(&mut self, target: u16) -> Result<bool, VmError> {
+        let mut hook = match self.hooks.remove(&target) {
+            Some(hook) => hook,
+            None => return Ok(false),
+        };
+        let mut regs = [0; REGISTERS];
+        for (idx, reg) in regs.iter_mut().enumerate() {
+            *reg = self.regs(idx as u16);
+        }
+        let handled = hook(&mut regs);
+        self.hooks.insert(target, hook);
+        if !handled {
+            return Ok(false);
+        }
+
+        for (idx, val) in regs.into_iter().enumerate() {
+            if val != self.regs(idx as u16) {
+                self.store(LIMIT + idx as u16, val % LIMIT)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{test_vm, StopReason};
+
+    #[test]
+    fn test_hook() {
+        // call 0x0005; out r0; halt; add r0 r0 1; ret
+        let program = vec![17, 5, 19, 32768, 0, 9, 32768, 32768, 1, 18];
+        let (mut vm, output) = test_vm(&program, "");
+        vm.set_reg(0, 'a' as u16);
+        let snapshot = vm.snapshot();
+
+        vm.add_hook(
+            5,
+            Box::new(|regs| {
+                regs[0] += 2;
+                true
+            }),
+        );
+        vm.enable_history(10);
+        assert_eq!(vm.run(), Ok(StopReason::Halted));
+        assert_eq!(output.contents(), "c");
+        assert!(vm.stack().is_empty());
+
+        // the hooked call undoes like any other instruction
+        vm.step_back();
+        vm.step_back();
+        assert_eq!((vm.ip(), vm.regs(0)), (0, 'a' as u16));
+
+        // a declining hook lets the routine run
+        vm.add_hook(5, Box::new(|_| false));
+        vm.restore(&snapshot);
+        output.clear();
+        assert_eq!(vm.run(), Ok(StopReason::Halted));
+        assert_eq!(output.contents(), "b");
+        assert_eq!(vm.hooks().collect::<Vec<_>>(), vec![5]);
+        assert!(vm.remove_hook(5));
+        assert!(!vm.remove_hook(5));
+    }
+}
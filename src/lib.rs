@@ -0,0 +1,35 @@
+//! An implementation of the Synacor Challenge virtual machine.
+//!
+//! The crate is split into a loader for program images, a symbol table for
+//! naming addresses, the instruction set description used by tooling, and the
+//! [`VM`] itself. The VM reports faults in the program as a [`VmError`] and
+//! talks to the outside world through pluggable [`Input`] and [`Output`]
+//! devices. The `synacore` binary is a thin client of this library.
+
+pub mod asm;
+pub mod cfg;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod discover;
+pub mod error;
+pub mod history;
+pub mod hooks;
+pub mod loader;
+pub mod opcode;
+pub mod patch;
+pub mod script;
+pub mod snapshot;
+pub mod solvers;
+pub mod symbols;
+pub mod trace;
+pub mod tracediff;
+pub mod vm;
+
+pub use device::{Input, Output};
+pub use error::VmError;
+pub use loader::read_input;
+pub use opcode::{Instruction, Opcode};
+pub use snapshot::Snapshot;
+pub use symbols::{read_symbols, SymbolTable};
+pub use vm::{Frame, Step, StopReason, VM};
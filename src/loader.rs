@@ -0,0 +1,88 @@
+//! Loading program images in the little-endian 16-bit Synacor format.
+
+use crate::vm::{LIMIT, REGISTERS};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::Cursor;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+
+/// The most words [`crate::VM::new`] can load: all of memory, and the
+/// registers that follow it.
+pub const MAX_IMAGE_WORDS: usize = LIMIT as usize + REGISTERS;
+
+/// Decodes raw bytes into 16-bit words. A trailing odd byte, or an image
+/// too big to load, is an error.
+pub fn decode_image(buffer: &[u8]) -> io::Result<Vec<u16>> {
+    if !buffer.len().is_multiple_of(2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("image has an odd number of bytes: {}", buffer.len()),
+        ));
+    }
+    if buffer.len() / 2 > MAX_IMAGE_WORDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "image has {} words, more than the {} that fit in memory",
+                buffer.len() / 2,
+                MAX_IMAGE_WORDS
+            ),
+        ));
+    }
+
+    let mut mem: Vec<u16> = vec![];
+    let mut rdr = Cursor::new(buffer);
+    while (rdr.position() as usize) < buffer.len() {
+        mem.push(rdr.read_u16::<LittleEndian>()?);
+    }
+
+    Ok(mem)
+}
+
+/// Reads a program image from disk, see [`decode_image`].
+pub fn read_input<P: AsRef<Path>>(filename: P) -> io::Result<Vec<u16>> {
+    let f = File::open(filename)?;
+    let mut reader = BufReader::new(f);
+    let mut buffer: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut buffer)?;
+
+    decode_image(&buffer)
+}
+
+/// Encodes words in the little-endian format [`decode_image`] reads.
+pub fn encode_image(mem: &[u16]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(mem.len() * 2);
+    for word in mem {
+        buffer.write_u16::<LittleEndian>(*word).unwrap();
+    }
+    buffer
+}
+
+/// Writes a program image to disk, see [`encode_image`].
+pub fn write_image<P: AsRef<Path>>(filename: P, mem: &[u16]) -> io::Result<()> {
+    let mut f = File::create(filename)?;
+    f.write_all(&encode_image(mem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_image() {
+        assert_eq!(
+            decode_image(&[0x09, 0x00, 0x00, 0x80]).unwrap(),
+            vec![9, 32768]
+        );
+        assert!(decode_image(&[0x09, 0x00, 0x00]).is_err());
+        assert_eq!(
+            decode_image(&vec![0; MAX_IMAGE_WORDS * 2]).unwrap().len(),
+            MAX_IMAGE_WORDS
+        );
+        let err = decode_image(&vec![0; MAX_IMAGE_WORDS * 2 + 2]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(encode_image(&[9, 32768]), vec![0x09, 0x00, 0x00, 0x80]);
+    }
+}
@@ -1,758 +1,438 @@
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::fs::File;
-use std::io;
-use std::io::Cursor;
-use std::io::Read;
-use std::io::{prelude::*, BufReader};
-use std::path::Path;
-
-#[macro_use]
-extern crate text_io;
-
-#[derive(Debug)]
-struct VM {
-    mem: Vec<u16>,
-    symbols: HashMap<u16, String>,
-    stack: Vec<u16>,
-    ip: usize,
-    input_buffer: VecDeque<char>,
+use std::error::Error;
+use std::fs;
+use std::process;
+use synacore::device::{BufferInput, BufferOutput, Input, Output, StdinInput, StdoutOutput};
+use synacore::loader::write_image;
+use synacore::patch::Patch;
+use synacore::script::{read_script, Recorder};
+use synacore::solvers::{coins, teleporter, vault, Game};
+use synacore::symbols::{format_symbols, write_symbols};
+use synacore::trace::{read_trace, TraceFilter, TraceFormat, Tracer};
+use synacore::{asm, cfg, disasm, discover, tracediff};
+use synacore::{read_input, read_symbols, StopReason, SymbolTable, VM};
+
+const USAGE: &str = "Usage: synacore run <image> [options]      run a program
+       synacore debug <image> [options]    run a program, starting at the debugger prompt
+       synacore disasm <image> [--symbols <file>]
+       synacore cfg <image> [function] [--symbols <file>]
+                                           print control-flow graphs as Graphviz DOT
+       synacore asm <source-file> <output-file>
+       synacore trace-diff <trace-a> <trace-b> [--symbols <file>]
+       synacore symbols <image> --discover [--symbols <file>] [--output <file>]
+                                           name every function the program calls,
+                                           found statically and from a run
+       synacore solve <puzzle> <image>      print the solution to a puzzle:
+                                           coins, vault, teleporter
+       synacore <image> [symbols-file] [options]
+                                           run with the built-in patch and walkthrough
+
+Options:
+  --symbols <file>          symbol file for names in listings and the debugger
+  --patch <file>            apply a patch file, may be repeated
+  --builtin-patch           apply the built-in teleporter patch
+  --no-builtin-patch        don't, even when running the legacy way
+  --script <file>           queue a script of commands as input, may be repeated
+  --walkthrough             queue the built-in walkthrough
+  --no-walkthrough          don't, even when running the legacy way
+  --trace <file>            write a record per instruction to file
+  --trace-format json|bin   format of the trace, json by default
+  --trace-range <addr[-end]>
+                            only trace instructions in this range (hex or symbols)
+  --trace-sym <name,...>    only trace instructions in these functions, may be repeated
+  --record <file>           save the input lines the game reads as a script
+  --record-debug            include debugger commands in the recording
+  --debug                   print every instruction to stderr
+  --discover                find functions for `symbols`
+  --output <file>           where `symbols` writes, stdout by default";
+
+type CliResult = Result<(), Box<dyn Error>>;
+
+/// Flags shared by the subcommands, and the positional arguments between them.
+#[derive(Debug, Default)]
+struct Options {
+    positional: Vec<String>,
+    symbols: Option<String>,
+    patches: Vec<String>,
+    builtin_patch: Option<bool>,
+    scripts: Vec<String>,
+    walkthrough: Option<bool>,
+    trace: Option<String>,
+    trace_format: Option<TraceFormat>,
+    trace_range: Option<String>,
+    trace_symbols: Vec<String>,
+    record: Option<String>,
+    record_debug: bool,
     debug: bool,
+    discover: bool,
+    output: Option<String>,
 }
 
-static LIMIT: u16 = 32768;
-
-impl VM {
-    pub fn new(input: &Vec<u16>, symbols: &HashMap<u16, String>) -> VM {
-        let size = LIMIT as usize + 8;
-        let mut mem = vec![0; size];
-        if input.len() > mem.len() {
-            panic!(
-                "Input buffer size out of bounds: {} > {}",
-                input.len(),
-                mem.len()
-            );
-        }
-        mem[0..input.len()].clone_from_slice(input);
-
-        VM {
-            mem: mem,
-            symbols: symbols.clone(),
-            stack: vec![],
-            ip: 0,
-            input_buffer: VecDeque::new(),
-            debug: false,
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--symbols" => opts.symbols = Some(value()?),
+            "--patch" => opts.patches.push(value()?),
+            "--builtin-patch" => opts.builtin_patch = Some(true),
+            "--no-builtin-patch" => opts.builtin_patch = Some(false),
+            "--script" => opts.scripts.push(value()?),
+            "--walkthrough" => opts.walkthrough = Some(true),
+            "--no-walkthrough" => opts.walkthrough = Some(false),
+            "--trace" => opts.trace = Some(value()?),
+            "--trace-range" => opts.trace_range = Some(value()?),
+            "--trace-sym" => opts
+                .trace_symbols
+                .extend(value()?.split(',').map(|s| s.to_string())),
+            "--trace-format" => {
+                opts.trace_format = match value()?.as_str() {
+                    "json" => Some(TraceFormat::Json),
+                    "bin" => Some(TraceFormat::Binary),
+                    other => return Err(format!("unknown trace format {}", other)),
+                }
+            }
+            "--record" => opts.record = Some(value()?),
+            "--record-debug" => opts.record_debug = true,
+            "--debug" => opts.debug = true,
+            "--discover" => opts.discover = true,
+            "--output" => opts.output = Some(value()?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => opts.positional.push(arg.clone()),
         }
     }
 
-    fn reg_offset(&self, arg: u16) -> u16 {
-        if arg >= LIMIT {
-            arg - LIMIT
-        } else {
-            arg
+    Ok(opts)
+}
+
+impl Options {
+    fn symbols(&self) -> Result<SymbolTable, Box<dyn Error>> {
+        match &self.symbols {
+            Some(path) => Ok(read_symbols(path)?),
+            None => Ok(SymbolTable::new()),
         }
     }
 
-    fn regs(&self, idx: u16) -> u16 {
-        if idx > 7 {
-            panic!("Invalid register: {}", idx);
-        }
-        self.mem[(LIMIT + idx) as usize]
+    /// Loads the image in the first positional argument and sets up the VM
+    /// as the options ask. `legacy` turns on the built-in patch and
+    /// walkthrough unless patches or scripts were given.
+    fn build_vm(&self, legacy: bool) -> Result<VM, Box<dyn Error>> {
+        self.build_vm_with(legacy, Box::new(StdinInput), Box::new(StdoutOutput))
     }
 
-    fn convert_arg(&self, addr: u16) -> u16 {
-        if addr > LIMIT + 8 {
-            panic!("Invalid addr: {}", addr);
+    /// Like [`Options::build_vm`], with the game's input and output going to
+    /// the given devices instead of the terminal.
+    fn build_vm_with(
+        &self,
+        legacy: bool,
+        input: Box<dyn Input>,
+        output: Box<dyn Output>,
+    ) -> Result<VM, Box<dyn Error>> {
+        let image = match self.positional.as_slice() {
+            [image] => image,
+            _ => usage(),
+        };
+        let mem = read_input(image)?;
+        let mut vm = VM::new(&mem, &self.symbols()?, input, output);
+        vm.set_debug(self.debug);
+
+        let builtin_patch = self
+            .builtin_patch
+            .unwrap_or(legacy && self.patches.is_empty());
+        if builtin_patch {
+            vm.patch()
+                .map_err(|err| format!("built-in patch: {}", err))?;
         }
-        if addr >= LIMIT {
-            self.mem[addr as usize]
-        } else {
-            addr
+        for path in &self.patches {
+            let res = Patch::load(path).and_then(|patch| Ok(patch.apply(&mut vm)?));
+            res.map_err(|err| format!("{}: {}", path, err))?;
         }
-    }
 
-    fn store(&mut self, addr: u16, val: u16) {
-        if addr > LIMIT + 8 {
-            panic!("Invalid addr: {}", addr);
+        if self
+            .walkthrough
+            .unwrap_or(legacy && self.scripts.is_empty())
+        {
+            vm.auto_play();
         }
-        self.mem[addr as usize] = val;
-    }
-
-    fn print_op(&self, op: &str) {
-        if self.debug {
-            eprintln!(
-            "{:04x}: {:<45} 0: {:04x} 1 {:04x} 2: {:04x} 3: {:04x} 4: {:04x} 5: {:04x} 6: {:04x} 7: {:04x} s({:>2}): {:04x}",
-            self.ip, op, self.regs(0), self.regs(1), self.regs(2), self.regs(3), self.regs(4), self.regs(5), self.regs(6), self.regs(7), self.stack.len(), self.stack.last().unwrap_or(&0)
-        );
+        for path in &self.scripts {
+            for line in read_script(path)? {
+                vm.push_input(&line);
+            }
         }
-    }
 
-    fn handle_debug(&mut self, line: &str) {
-        let parts: &Vec<&str> = &line[1..].split(" ").collect();
-        match parts[0] {
-            "wmem" => {
-                if parts.len() >= 3 {
-                    let addr = u16::from_str_radix(parts[1], 16);
-                    let val = u16::from_str_radix(parts[2], 16);
-
-                    if addr.as_ref().is_ok() && val.as_ref().is_ok() {
-                        let a = addr.unwrap();
-                        let v = val.unwrap();
-                        println!("DEBUG: wmem {:04x} {:04x}", a, v);
-                        self.mem[a as usize] = v;
-                    } else {
-                        println!("DEBUG: error parsing arguments for wmem");
-                    }
-                } else {
-                    println!("DEBUG: not enough arguments for wmem");
-                }
-            }
-            "wreg" => {
-                if parts.len() >= 3 {
-                    let reg = u16::from_str_radix(parts[1], 10);
-                    let val = u16::from_str_radix(parts[2], 10);
-
-                    if reg.as_ref().is_ok() && val.as_ref().is_ok() {
-                        let r = reg.unwrap();
-                        if r > 7 {
-                            println!("DEBUG: invalid register: {}", r);
-                        } else {
-                            let v = val.unwrap();
-                            println!("DEBUG: wreg {} {}", r, v);
-                            self.mem[r as usize + LIMIT as usize] = v;
-                        }
-                    } else {
-                        println!("DEBUG: error parsing arguments for wreg");
-                    }
-                } else {
-                    println!("DEBUG: not enough arguments for wreg");
-                }
+        let filter = TraceFilter {
+            range: match &self.trace_range {
+                Some(range) => Some(
+                    TraceFilter::parse_range(range, vm.symbols())
+                        .ok_or(format!("invalid trace range {}", range))?,
+                ),
+                None => None,
+            },
+            symbols: self.trace_symbols.clone(),
+        };
+        match &self.trace {
+            Some(path) => vm.start_trace(Tracer::create(
+                path,
+                self.trace_format.unwrap_or(TraceFormat::Json),
+                filter,
+            )?),
+            None if filter != TraceFilter::default() => {
+                return Err("--trace-range and --trace-sym need --trace".into())
             }
-            "debug" => {
-                self.debug = !self.debug;
-                println!(
-                    "DEBUG: switched debug mode {}",
-                    if self.debug { "on " } else { "off" }
-                )
-            }
-            _ => {}
+            None => {}
         }
-        println!("");
-    }
-
-    fn add_to_buffer(&mut self, input: &str) {
-        for c in input.chars() {
-            self.input_buffer.push_back(c);
+        if let Some(path) = &self.record {
+            vm.start_recording(Recorder::create(path, self.record_debug)?);
         }
-        self.input_buffer.push_back('\n');
+        Ok(vm)
     }
+}
 
-    pub fn auto_play(&mut self) {
-        self.add_to_buffer("take tablet");
-        self.add_to_buffer("use tablet");
-
-        self.add_to_buffer("go doorway");
-        self.add_to_buffer("go north");
-        self.add_to_buffer("go north");
-        self.add_to_buffer("go bridge");
-        self.add_to_buffer("go continue");
-        self.add_to_buffer("go down");
-        self.add_to_buffer("go east");
-        self.add_to_buffer("take empty lantern");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go passage");
-        self.add_to_buffer("go ladder");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go south");
-        self.add_to_buffer("go north");
-        self.add_to_buffer("take can");
-        self.add_to_buffer("use can");
-        self.add_to_buffer("use lantern");
-
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go ladder");
-        self.add_to_buffer("go darkness");
-        self.add_to_buffer("continue");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("go north");
-        self.add_to_buffer("take red coin");
-        self.add_to_buffer("go north");
-        self.add_to_buffer("go west");
-        self.add_to_buffer("take blue coin");
-        self.add_to_buffer("go up");
-        self.add_to_buffer("take shiny coin");
-        self.add_to_buffer("go down");
-        self.add_to_buffer("go east");
-        self.add_to_buffer("go east");
-        self.add_to_buffer("take concave coin");
-        self.add_to_buffer("go down");
-        self.add_to_buffer("take corroded coin");
-        self.add_to_buffer("go up");
-        self.add_to_buffer("go west");
-
-        // (9, 2, 5, 7, 3), see brute-coins.py
-        self.add_to_buffer("use blue coin"); // == 9
-        self.add_to_buffer("use red coin"); // == 2
-        self.add_to_buffer("use shiny coin"); // == 5
-        self.add_to_buffer("use concave coin"); // == 7
-        self.add_to_buffer("use corroded coin"); // == 3
-
-        self.add_to_buffer("go north");
-        self.add_to_buffer("take teleporter");
-        self.add_to_buffer("use teleporter");
-
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("east");
-        self.add_to_buffer("take journal");
-        self.add_to_buffer("look journal");
-        self.add_to_buffer("west");
-        self.add_to_buffer("north");
-        self.add_to_buffer("north");
-        self.add_to_buffer("take orb");
-
-        // see vault.png and brute-vault.py
-        // 22 + 4 - 11 * 4 - 18 - 11 - 1
-        self.add_to_buffer("north"); // +
-        self.add_to_buffer("east"); // 4
-        self.add_to_buffer("east"); // -
-        self.add_to_buffer("north"); // 11
-        self.add_to_buffer("west"); // *
-        self.add_to_buffer("south"); // 4
-        self.add_to_buffer("east"); // -
-        self.add_to_buffer("east"); //18
-        self.add_to_buffer("west"); // -
-        self.add_to_buffer("north"); // 11
-        self.add_to_buffer("north"); // -
-        self.add_to_buffer("east"); // 1
-        self.add_to_buffer("vault");
-
-        self.add_to_buffer("take mirror");
-        self.add_to_buffer("use mirror");
+fn run(opts: &Options, legacy: bool, prompt_first: bool) -> CliResult {
+    let mut vm = opts.build_vm(legacy)?;
+    if !prompt_first || vm.debug_prompt()? {
+        vm.run_interactive()?;
     }
+    vm.stop_trace()?;
+    Ok(())
+}
 
-    pub fn patch(&mut self) {
-        // patch out self test on reg 7
-        self.mem[7 as usize + LIMIT as usize] = 25734;
-        self.mem[0x0209] = 8;
-        // patch out the recursive call
-        self.mem[0x156D] = 6;
-        self.mem[0x1571] = 21;
-        self.mem[0x1572] = 21;
+/// The symbols from `--symbols`, or from the positional argument at `idx`
+/// where older command lines put them.
+fn positional_symbols(opts: &Options, idx: usize) -> Result<SymbolTable, Box<dyn Error>> {
+    match opts.positional.get(idx) {
+        Some(path) if opts.symbols.is_none() => Ok(read_symbols(path)?),
+        Some(_) => usage(),
+        None => opts.symbols(),
     }
+}
 
-    pub fn run(&mut self) {
-        loop {
-            if self.ip + 1 > self.mem.len() {
-                println!("ran outside of memory range at ip={}", self.ip);
-                break;
-            }
+fn disasm(opts: &Options) -> CliResult {
+    let image = match opts.positional.as_slice() {
+        [image] | [image, _] => image,
+        _ => usage(),
+    };
 
-            let instr = self.mem[self.ip];
+    let mem = read_input(image)?;
+    let table = positional_symbols(opts, 1)?;
+    print!("{}", disasm::listing(&mem, &table, 0..mem.len()));
+    Ok(())
+}
 
-            match instr {
-                0 => {
-                    // halt 0: stop execution and terminate the program
-                    self.print_op("halt");
-                    break;
-                }
-                1 => {
-                    // set 1 a b: set register <a> to the value of <b>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let b_val = self.convert_arg(self.mem[self.ip + 2]);
-                    self.store(a, b_val);
-
-                    self.print_op(&format!(
-                        "set  {} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        a,
-                        self.reg_offset(b),
-                        b_val
-                    ));
-                    self.ip += 3;
-                }
-                2 => {
-                    // push: 2 a: push <a> onto the stack
-                    let a = self.mem[self.ip + 1];
-                    let a_val = self.convert_arg(a);
-                    self.stack.push(a_val);
-
-                    self.print_op(&format!(
-                        "push   {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        a_val
-                    ));
-                    self.ip += 2;
-                }
-                3 => {
-                    // pop: 3 a: remove the top element from the stack and write it into <a>; empty stack = error
-                    let a = self.mem[self.ip + 1];
-                    let val = self.stack.pop().unwrap();
-                    self.store(a, val);
-
-                    self.print_op(&format!(
-                        "pop  {} {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        a,
-                        val
-                    ));
-                    self.ip += 2;
-                }
-                4 => {
-                    // eq: 4 a b c: set <a> to 1 if <b> is equal to <c>; set it to 0 otherwise
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    if b_val == c_val {
-                        self.store(a, 1);
-                    } else {
-                        self.store(a, 0);
-                    }
-
-                    self.print_op(&format!(
-                        "eq   {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                5 => {
-                    // gt: 5 a b c: set <a> to 1 if <b> is greater than <c>; set it to 0 otherwise
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    if b_val > c_val {
-                        self.store(a, 1);
-                    } else {
-                        self.store(a, 0);
-                    }
-
-                    self.print_op(&format!(
-                        "gt   {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                6 => {
-                    // jmp: 6 a: jump to <a>
-                    let a = self.mem[self.ip + 1];
-                    let arg = self.convert_arg(a);
+fn control_flow(opts: &Options) -> CliResult {
+    let (image, function) = match opts.positional.as_slice() {
+        [image] => (image, None),
+        [image, function] => (image, Some(function)),
+        _ => usage(),
+    };
 
-                    self.print_op(&format!("jmp    {:04x} ({:04x})", a, arg));
-                    self.ip = arg as usize;
-                }
-                7 => {
-                    // jt: 7 a b: if <a> is nonzero, jump to <b>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let a_val = self.convert_arg(a);
-                    let b_val = self.convert_arg(b);
-
-                    self.print_op(&format!(
-                        "jnz    {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        a_val,
-                        self.reg_offset(b),
-                        b_val
-                    ));
-                    if a_val != 0 {
-                        self.ip = b_val as usize;
-                    } else {
-                        self.ip += 3;
-                    }
-                }
-                8 => {
-                    // jf: 8 a b: if <a> is zero, jump to <b>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let a_val = self.convert_arg(a);
-                    let b_val = self.convert_arg(b);
-
-                    self.print_op(&format!(
-                        "jz     {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        a_val,
-                        self.reg_offset(b),
-                        b_val
-                    ));
-                    if a_val == 0 {
-                        self.ip = b_val as usize;
-                    } else {
-                        self.ip += 3;
-                    }
-                }
-                9 => {
-                    // add: 9 a b c: assign into <a> the sum of <b> and <c> (modulo 32768)
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    let r = (b_val + c_val) % LIMIT;
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "add  {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                10 => {
-                    // mult: 10 a b c: store into <a> the product of <b> and <c> (modulo 32768)
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    let r = ((b_val as u32 * c_val as u32) % LIMIT as u32) as u16;
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "mult {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                11 => {
-                    // mod: 11 a b c: store into <a> the remainder of <b> divided by <c>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    let r = b_val % c_val;
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "mod  {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                12 => {
-                    // and: 12 a b c: stores into <a> the bitwise and of <b> and <c>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    let r = b_val & c_val;
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "and  {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                13 => {
-                    // or: 13 a b c: stores into <a> the bitwise or of <b> and <c>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let c = self.mem[self.ip + 3];
-                    let b_val = self.convert_arg(b);
-                    let c_val = self.convert_arg(c);
-
-                    let r = b_val | c_val;
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "or   {} {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val,
-                        self.reg_offset(c),
-                        c_val
-                    ));
-                    self.ip += 4;
-                }
-                14 => {
-                    // not: 14 a b: stores 15-bit bitwise inverse of <b> in <a>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let b_val = self.convert_arg(b);
-
-                    let r = !b_val & 0b0111_1111_1111_1111;
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "not  {} {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val
-                    ));
-                    self.ip += 3;
-                }
-                15 => {
-                    // rmem: 15 a b: read memory at address <b> and write it to <a>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let b_val = self.convert_arg(b);
-
-                    let r = self.mem[b_val as usize];
-                    self.store(a, r);
-
-                    self.print_op(&format!(
-                        "rmem {} {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        self.reg_offset(b),
-                        b_val
-                    ));
-                    self.ip += 3;
-                }
-                16 => {
-                    // wmem: 16 a b: write the value from <b> into memory at address <a>
-                    let a = self.mem[self.ip + 1];
-                    let b = self.mem[self.ip + 2];
-                    let a_val = self.convert_arg(a);
-                    let b_val = self.convert_arg(b);
-
-                    self.mem[a_val as usize] = b_val;
-
-                    self.print_op(&format!(
-                        "wmem {:04x} ({:04x}) {:04x} ({:04x})",
-                        self.reg_offset(a),
-                        a_val,
-                        self.reg_offset(b),
-                        b_val
-                    ));
-                    self.ip += 3;
-                }
-                17 => {
-                    // call: 17 a: write the address of the next instruction to the stack and jump to <a>
-                    let a = self.mem[self.ip + 1];
-                    let a_val = self.convert_arg(a);
-                    self.stack.push((self.ip + 2) as u16);
-
-                    let symbol = self.symbols.get(&a_val);
-
-                    if symbol.is_none() {
-                        self.print_op(&format!("call {:04x} ({:04x})", self.reg_offset(a), a_val));
-                    } else {
-                        self.print_op(&format!(
-                            "call {} {:04x} ({:04x})",
-                            symbol.unwrap(),
-                            self.reg_offset(a),
-                            a_val
-                        ));
-                    }
-
-                    if self.debug {
-                        eprintln!("");
-                        if let Some(sym) = symbol {
-                            eprintln!("{}:", sym);
-                        }
-                    }
-                    self.ip = a_val as usize;
-                }
-                18 => {
-                    // ret: 18: remove the top element from the stack and jump to it; empty stack = halt
-                    if self.stack.len() == 0 {
-                        break;
-                    }
-                    let val = self.stack.pop().unwrap();
-
-                    self.print_op(&format!("ret  {:04x}", val));
-                    if self.debug {
-                        eprintln!("");
-                    }
-                    self.ip = val as usize;
-                }
-                19 => {
-                    // out: 19 a: write the character represented by ascii code <a> to the terminal
-                    let a = self.mem[self.ip + 1];
-                    let a_val = self.convert_arg(a);
-                    let val = a_val as u8 as char;
-                    print!("{}", val);
-
-                    let mut debug_val: &str = &val.to_string();
-                    if val == '\n' {
-                        debug_val = "\\n";
-                        /*self.debug = !self.debug;
-                        self.print_op("dbg");
-                        self.debug = !self.debug;*/
-                    }
-                    self.print_op(&format!(
-                        "out    {:04x} ({})",
-                        self.reg_offset(a),
-                        debug_val
-                    ));
-                    self.ip += 2;
-                }
-                20 => {
-                    // in: 20 a: read a character from the terminal and write its ascii code to <a>;
-                    // it can be assumed that once input starts, it will continue until a newline
-                    // is encountered;
-                    // this means that you can safely read whole lines from the keyboard
-                    // and trust that they will be fully read
-                    if self.input_buffer.len() == 0 {
-                        while self.input_buffer.len() == 0 {
-                            let input: String = read!("{}\n");
-                            for c in input.chars() {
-                                self.input_buffer.push_back(c);
-                            }
-                            self.input_buffer.push_back('\n');
-
-                            if self.input_buffer[0] == '.' {
-                                self.handle_debug(&input);
-                                self.input_buffer.clear();
-                            }
-                        }
-                    }
-
-                    let a = self.mem[self.ip + 1];
-                    let val = self.input_buffer.pop_front().unwrap();
-                    let r = val as u16;
-                    self.store(a, r);
-
-                    let mut debug_val: &str = &val.to_string();
-                    if val == '\n' {
-                        debug_val = "\\n";
-                    }
-                    self.print_op(&format!(
-                        "in     {:04x} {:04x} ({})",
-                        self.reg_offset(a),
-                        r,
-                        debug_val
-                    ));
-
-                    self.ip += 2;
-                }
-                21 => {
-                    // noop: 21: no operation
-                    self.print_op("noop");
-                    self.ip += 1;
-                }
-                _ => {
-                    panic!("not sure what to do with instruction {}", instr);
-                }
+    let mem = read_input(image)?;
+    let table = opts.symbols()?;
+    let function = match function {
+        Some(name) => Some(
+            table
+                .address_of(name)
+                .or_else(|| u16::from_str_radix(name, 16).ok())
+                .ok_or(format!("unknown function {}", name))?,
+        ),
+        None => None,
+    };
+
+    // much of challenge.bin is only reached through code built at run time,
+    // so known symbols are entry points too
+    let mut entries = vec![0];
+    entries.extend(table.iter().map(|(addr, _)| addr));
+    entries.extend(function);
+    let graph = cfg::recover(&mem, &entries);
+    match function {
+        Some(addr) => print!("{}", graph.to_dot(addr, &mem, &table).unwrap()),
+        None => {
+            for addr in graph.functions.keys() {
+                print!("{}", graph.to_dot(*addr, &mem, &table).unwrap());
             }
         }
     }
+    Ok(())
 }
 
-fn read_input(filename: &str) -> Result<Vec<u16>, io::Error> {
-    let f = File::open(filename)?;
-    let mut reader = BufReader::new(f);
-    let mut buffer: Vec<u8> = Vec::new();
-    reader.read_to_end(&mut buffer)?;
-    let len = buffer.len();
-
-    let mut mem: Vec<u16> = vec![];
-    let mut rdr = Cursor::new(buffer);
-    while (rdr.position() as usize) < len {
-        let val = rdr.read_u16::<LittleEndian>().unwrap();
-        mem.push(val);
-    }
-
-    Ok(mem)
-}
+fn symbols(opts: &Options) -> CliResult {
+    let image = match opts.positional.as_slice() {
+        [image] if opts.discover => image,
+        _ => usage(),
+    };
 
-fn read_symbols(filename: &str) -> HashMap<u16, String> {
-    let file = File::open(Path::new(filename)).expect("No such file");
-    let buf = BufReader::new(file);
-    let mut table = HashMap::new();
-    let lines: Vec<String> = buf
-        .lines()
-        .map(|l| l.expect("Could not parse line"))
-        .collect();
-
-    for line in lines {
-        let parts = line
-            .split(",")
-            .map(|p| p.to_string())
-            .collect::<Vec<String>>();
-        table.insert(
-            u16::from_str_radix(&parts[0], 16).unwrap(),
-            parts[1].to_string(),
+    let mem = read_input(image)?;
+    let table = opts.symbols()?;
+    let mut entries = vec![0];
+    entries.extend(table.iter().map(|(addr, _)| addr));
+    let mut calls = discover::static_calls(&mem, &entries);
+
+    // the run reaches the calls made through registers. With no patches or
+    // scripts given it plays through the game the legacy way, if the image
+    // is one the built-in patch applies to
+    let mut vm = opts.build_vm_with(
+        false,
+        Box::new(BufferInput::new("")),
+        Box::new(BufferOutput::new()),
+    )?;
+    let defaults = opts.builtin_patch.is_none()
+        && opts.walkthrough.is_none()
+        && opts.patches.is_empty()
+        && opts.scripts.is_empty();
+    if defaults && vm.patch().is_ok() {
+        vm.auto_play();
+    }
+    let (found, reason) = discover::run_calls(&mut vm, discover::RUN_BUDGET)?;
+    calls.extend(found);
+    vm.stop_trace()?;
+    if reason == StopReason::StepLimit {
+        eprintln!(
+            "warning: stopped the run after {} instructions, it may have more calls",
+            discover::RUN_BUDGET
         );
     }
 
-    table
+    let named = discover::name_functions(&table, &calls);
+    eprintln!(
+        "{} functions called, {} newly named",
+        calls.len(),
+        named.len() - table.len()
+    );
+    match &opts.output {
+        Some(path) => write_symbols(path, &named)?,
+        None => print!("{}", format_symbols(&named)),
+    }
+    Ok(())
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        panic!("Usage: synacore <file-to-execute> [optionfal-symbols-file]");
-    }
+fn assemble(opts: &Options) -> CliResult {
+    let (src_path, out_path) = match opts.positional.as_slice() {
+        [src, out] => (src, out),
+        _ => usage(),
+    };
+
+    let src = fs::read_to_string(src_path)?;
+    let image = asm::assemble(&src).map_err(|err| format!("{}: {}", src_path, err))?;
+    write_image(out_path, &image)?;
+    Ok(())
+}
 
-    let mem = read_input(&args[1]).unwrap();
-    let table = if args.len() > 2 {
-        read_symbols(&args[2])
-    } else {
-        HashMap::new()
+fn trace_diff(opts: &Options) -> CliResult {
+    let (a, b) = match opts.positional.as_slice() {
+        [a, b] | [a, b, _] => (read_trace(a)?, read_trace(b)?),
+        _ => usage(),
     };
 
-    let mut vm = VM::new(&mem, &table);
-    //vm.debug = true;
-    vm.patch();
-    vm.auto_play();
-    vm.run();
+    match tracediff::diff(&a, &b) {
+        Some(divergence) => {
+            print!("{}", divergence.report(&positional_symbols(opts, 2)?));
+            process::exit(1);
+        }
+        None => println!("traces are identical ({} records)", a.len()),
+    }
+    Ok(())
+}
 
+fn solve(opts: &Options) -> CliResult {
+    let (puzzle, image) = match opts.positional.as_slice() {
+        [puzzle, image] => (puzzle.as_str(), image),
+        _ => usage(),
+    };
+    let program = read_input(image)?;
+
+    match puzzle {
+        "coins" => {
+            let game = Game::boot(&program)?;
+            let found = coins::read_coins(game.vm.mem());
+            let order = coins::solve(&found).ok_or("no order of the coins solves the equation")?;
+            let values: Vec<u16> = order.iter().map(|c| c.value).collect();
+            println!(
+                "# {} + {} * {}^2 + {}^3 - {} = {}",
+                values[0],
+                values[1],
+                values[2],
+                values[3],
+                values[4],
+                coins::TARGET
+            );
+            for cmd in coins::commands(&order) {
+                println!("{}", cmd);
+            }
+        }
+        "vault" => {
+            let grid = vault::explore(&mut vault::antechamber(&program)?)?;
+            let moves = vault::solve(&grid).ok_or("no walk through the grid opens the vault")?;
+            println!("# {}", vault::expression(&grid, &moves));
+            for cmd in vault::commands(&moves) {
+                println!("{}", cmd);
+            }
+        }
+        "teleporter" => {
+            let check = teleporter::find_check(&program).ok_or("no teleporter check found")?;
+            let found = teleporter::search(&check);
+            let r7 = *found
+                .first()
+                .ok_or("no energy level passes the confirmation")?;
+            print!("{}", teleporter::patch(&check, r7));
+        }
+        _ => return Err(format!("unknown puzzle {}", puzzle).into()),
+    }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        usage();
+    }
 
-    #[test]
-    fn test_simple() {
-        // - The program "9,32768,32769,4,19,32768" occupies six memory addresses and should:
-        //  - Store into register 0 the sum of 4 and the value contained in register 1.
-        //  - Output to the terminal the character with the ascii code contained in register 0.
+    let (command, rest) = match args[0].as_str() {
+        "run" | "debug" | "disasm" | "cfg" | "symbols" | "asm" | "trace-diff" | "solve" => {
+            (args[0].as_str(), &args[1..])
+        }
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            return;
+        }
+        _ => ("legacy", &args[..]),
+    };
+    let mut opts = match parse_options(rest) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            usage();
+        }
+    };
 
-        let program = vec![9, 32768, 32769, 4, 19, 32768];
-        let mut vm = VM::new(&program, &HashMap::new());
-        vm.debug = true;
-        vm.run();
+    let res = match command {
+        "run" => run(&opts, false, false),
+        "debug" => run(&opts, false, true),
+        "disasm" => disasm(&opts),
+        "cfg" => control_flow(&opts),
+        "symbols" => symbols(&opts),
+        "asm" => assemble(&opts),
+        "trace-diff" => trace_diff(&opts),
+        "solve" => solve(&opts),
+        _ => {
+            // synacore <image> [symbols-file]
+            match opts.positional.len() {
+                1 => {}
+                2 if opts.symbols.is_none() => opts.symbols = Some(opts.positional.remove(1)),
+                _ => usage(),
+            }
+            run(&opts, true, false)
+        }
+    };
 
-        assert_eq!(vm.regs(0), 4);
-        assert_eq!(vm.ip, 6);
+    if let Err(err) = res {
+        eprintln!("error: {}", err);
+        process::exit(1);
     }
 }
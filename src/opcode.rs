@@ -0,0 +1,164 @@
+//! The Synacor instruction set: opcode numbers, mnemonics and operand counts.
+
+/// One of the 22 instructions understood by the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Halt,
+    Set,
+    Push,
+    Pop,
+    Eq,
+    Gt,
+    Jmp,
+    Jt,
+    Jf,
+    Add,
+    Mult,
+    Mod,
+    And,
+    Or,
+    Not,
+    Rmem,
+    Wmem,
+    Call,
+    Ret,
+    Out,
+    In,
+    Noop,
+}
+
+static OPCODES: [Opcode; 22] = [
+    Opcode::Halt,
+    Opcode::Set,
+    Opcode::Push,
+    Opcode::Pop,
+    Opcode::Eq,
+    Opcode::Gt,
+    Opcode::Jmp,
+    Opcode::Jt,
+    Opcode::Jf,
+    Opcode::Add,
+    Opcode::Mult,
+    Opcode::Mod,
+    Opcode::And,
+    Opcode::Or,
+    Opcode::Not,
+    Opcode::Rmem,
+    Opcode::Wmem,
+    Opcode::Call,
+    Opcode::Ret,
+    Opcode::Out,
+    Opcode::In,
+    Opcode::Noop,
+];
+
+impl Opcode {
+    /// Decodes a raw memory word, returning `None` for anything above 21.
+    pub fn from_u16(val: u16) -> Option<Opcode> {
+        OPCODES.get(val as usize).copied()
+    }
+
+    /// Looks up an opcode by the mnemonic returned from [`Opcode::name`].
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        OPCODES.iter().copied().find(|op| op.name() == name)
+    }
+
+    /// The numeric encoding of this opcode.
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    /// The mnemonic used in debug output, disassembly and assembly.
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Halt => "halt",
+            Opcode::Set => "set",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Eq => "eq",
+            Opcode::Gt => "gt",
+            Opcode::Jmp => "jmp",
+            Opcode::Jt => "jnz",
+            Opcode::Jf => "jz",
+            Opcode::Add => "add",
+            Opcode::Mult => "mult",
+            Opcode::Mod => "mod",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Not => "not",
+            Opcode::Rmem => "rmem",
+            Opcode::Wmem => "wmem",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::Out => "out",
+            Opcode::In => "in",
+            Opcode::Noop => "noop",
+        }
+    }
+
+    /// Number of operands following the opcode word.
+    pub fn arity(self) -> usize {
+        match self {
+            Opcode::Halt | Opcode::Ret | Opcode::Noop => 0,
+            Opcode::Push | Opcode::Pop | Opcode::Jmp | Opcode::Call | Opcode::Out | Opcode::In => 1,
+            Opcode::Set | Opcode::Jt | Opcode::Jf | Opcode::Not | Opcode::Rmem | Opcode::Wmem => 2,
+            Opcode::Eq
+            | Opcode::Gt
+            | Opcode::Add
+            | Opcode::Mult
+            | Opcode::Mod
+            | Opcode::And
+            | Opcode::Or => 3,
+        }
+    }
+
+    /// Total number of words occupied by the instruction, opcode included.
+    pub fn size(self) -> usize {
+        1 + self.arity()
+    }
+}
+
+/// A decoded instruction as found in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<u16>,
+}
+
+impl Instruction {
+    /// Decodes the instruction at `addr`, or `None` if the word there is not a
+    /// valid opcode or its operands run past the end of `mem`.
+    pub fn decode(mem: &[u16], addr: usize) -> Option<Instruction> {
+        let opcode = Opcode::from_u16(*mem.get(addr)?)?;
+        let operands = mem.get(addr + 1..addr + opcode.size())?.to_vec();
+        Some(Instruction {
+            addr,
+            opcode,
+            operands,
+        })
+    }
+
+    /// Address of the instruction that follows this one.
+    pub fn next(&self) -> usize {
+        self.addr + self.opcode.size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mem = vec![9, 32768, 32769, 4, 19, 32768, 22];
+        let add = Instruction::decode(&mem, 0).unwrap();
+        assert_eq!(add.opcode, Opcode::Add);
+        assert_eq!(add.operands, vec![32768, 32769, 4]);
+        assert_eq!(add.next(), 4);
+
+        assert_eq!(Instruction::decode(&mem, 4).unwrap().opcode, Opcode::Out);
+        assert_eq!(Instruction::decode(&mem, 6), None);
+        assert_eq!(Opcode::from_name("jnz"), Some(Opcode::Jt));
+    }
+}
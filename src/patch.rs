@@ -0,0 +1,260 @@
+//! Patch files: declarative edits to a loaded program.
+//!
+//! ```text
+//! # comments run to the end of the line
+//! reg 7 25734                    # set a register
+//! mem 0x0209 8 expect 7          # write words, checking what they replace
+//! asm 0x1571 expect 17 0x178b    # assemble a snippet in place
+//!     noop                       ; inside the block, comments use `;`
+//!     noop
+//! end
+//! ```
+//!
+//! Numbers are decimal or `0x` hex. `mem` writes one or more words starting
+//! at the address. With `expect`, the patch is refused unless memory holds
+//! exactly those words beforehand, so a patch for one binary does not
+//! silently corrupt another. `asm` blocks use the syntax of [`crate::asm`],
+//! with `;` comments, and labels resolve relative to the block's address.
+
+use crate::asm::{assemble_at, parse_number};
+use crate::vm::{LIMIT, REGISTERS, VM};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The patch [`VM::patch`] applies, for `challenge.bin`, as generated by
+/// [`crate::solvers::teleporter`].
+pub const TELEPORTER: &str = include_str!("../teleporter.patch");
+
+/// A problem parsing or applying a patch, with the 1-based line it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for PatchError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Edit {
+    Mem {
+        addr: u16,
+        values: Vec<u16>,
+        expect: Option<Vec<u16>>,
+    },
+    Reg {
+        reg: u16,
+        value: u16,
+    },
+}
+
+/// A parsed patch file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Patch {
+    /// Each edit with the line it was declared on.
+    edits: Vec<(usize, Edit)>,
+}
+
+fn number(s: &str) -> Result<u16, String> {
+    parse_number(s).ok_or(format!("invalid number {}", s))
+}
+
+fn address(s: &str) -> Result<u16, String> {
+    number(s).and_then(|addr| {
+        Some(addr)
+            .filter(|addr| *addr < LIMIT)
+            .ok_or(format!("invalid address {}", s))
+    })
+}
+
+/// Splits `mem`/`asm` arguments into the words to write and the words
+/// expected after `expect`.
+fn split_expect(args: &[&str]) -> Result<(Vec<u16>, Option<Vec<u16>>), String> {
+    let (values, expect) = match args.iter().position(|a| *a == "expect") {
+        Some(idx) => (&args[..idx], Some(&args[idx + 1..])),
+        None => (args, None),
+    };
+    let values = values.iter().map(|v| number(v)).collect::<Result<_, _>>()?;
+    let expect = match expect {
+        Some([]) => return Err("expect needs at least one value".to_string()),
+        Some(expect) => Some(expect.iter().map(|v| number(v)).collect::<Result<_, _>>()?),
+        None => None,
+    };
+    Ok((values, expect))
+}
+
+impl Patch {
+    /// Parses the text of a patch file.
+    pub fn parse(text: &str) -> Result<Patch, PatchError> {
+        let mut edits = vec![];
+        let mut lines = text.lines().enumerate().map(|(idx, line)| (idx + 1, line));
+
+        while let Some((line, text)) = lines.next() {
+            let err = |message| PatchError { line, message };
+            let words: Vec<&str> = match text.split_once('#') {
+                Some((text, _)) => text.split_whitespace().collect(),
+                None => text.split_whitespace().collect(),
+            };
+
+            let edit = match words.as_slice() {
+                [] => continue,
+                ["reg", reg, value] => {
+                    let reg = number(reg)
+                        .ok()
+                        .filter(|r| (*r as usize) < REGISTERS)
+                        .ok_or(err(format!("invalid register {}", reg)))?;
+                    let value = number(value)
+                        .ok()
+                        .filter(|v| *v < LIMIT)
+                        .ok_or(err(format!("invalid register value {}", value)))?;
+                    Edit::Reg { reg, value }
+                }
+                ["mem", addr, args @ ..] => {
+                    let addr = address(addr).map_err(err)?;
+                    let (values, expect) = split_expect(args).map_err(err)?;
+                    if values.is_empty() {
+                        return Err(err("mem needs at least one value".to_string()));
+                    }
+                    Edit::Mem {
+                        addr,
+                        values,
+                        expect,
+                    }
+                }
+                ["asm", addr, args @ ..] => {
+                    let addr = address(addr).map_err(err)?;
+                    let (values, expect) = split_expect(args).map_err(err)?;
+                    if !values.is_empty() {
+                        return Err(err("asm only takes an address and expect".to_string()));
+                    }
+
+                    let mut src = vec![];
+                    loop {
+                        match lines.next() {
+                            Some((_, text)) if text.trim() == "end" => break,
+                            Some((_, text)) => src.push(text),
+                            None => return Err(err("asm block without end".to_string())),
+                        }
+                    }
+                    let values =
+                        assemble_at(&src.join("\n"), addr as usize).map_err(|e| PatchError {
+                            line: line + e.line,
+                            message: e.message,
+                        })?;
+                    Edit::Mem {
+                        addr,
+                        values,
+                        expect,
+                    }
+                }
+                _ => return Err(err(format!("invalid patch line: {}", text.trim()))),
+            };
+            edits.push((line, edit));
+        }
+
+        Ok(Patch { edits })
+    }
+
+    /// Reads and parses a patch file from disk.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Patch, Box<dyn Error>> {
+        Ok(Patch::parse(&fs::read_to_string(path)?)?)
+    }
+
+    /// Applies every edit to `vm`. All `expect` checks are made first, so a
+    /// mismatch leaves memory untouched.
+    pub fn apply(&self, vm: &mut VM) -> Result<(), PatchError> {
+        for (line, edit) in &self.edits {
+            let (addr, values, expect) = match edit {
+                Edit::Mem {
+                    addr,
+                    values,
+                    expect,
+                } => (*addr, values, expect),
+                Edit::Reg { .. } => continue,
+            };
+            let err = |message| PatchError {
+                line: *line,
+                message,
+            };
+
+            let start = addr as usize;
+            if start + values.len() > LIMIT as usize {
+                return Err(err(format!(
+                    "patch at {:04x} runs past the end of memory",
+                    addr
+                )));
+            }
+            if let Some(expect) = expect {
+                let found = &vm.mem()[start..(start + expect.len()).min(LIMIT as usize)];
+                if found != expect.as_slice() {
+                    let hex = |words: &[u16]| {
+                        let words: Vec<String> =
+                            words.iter().map(|w| format!("{:04x}", w)).collect();
+                        words.join(" ")
+                    };
+                    return Err(err(format!(
+                        "expected {} at {:04x}, found {}",
+                        hex(expect),
+                        addr,
+                        hex(found)
+                    )));
+                }
+            }
+        }
+
+        for (_, edit) in &self.edits {
+            match edit {
+                Edit::Mem { addr, values, .. } => {
+                    let start = *addr as usize;
+                    vm.mem_mut()[start..start + values.len()].copy_from_slice(values);
+                }
+                Edit::Reg { reg, value } => vm.set_reg(*reg, *value),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::test_vm;
+
+    #[test]
+    fn test_apply() {
+        let patch = Patch::parse(
+            "reg 7 0x10 # comment\n\nmem 1 5 6 expect 2 3\nasm 4 expect 0\n  jmp here\nhere: noop ; ok\nend\n",
+        )
+        .unwrap();
+        let (mut vm, _) = test_vm(&[1, 2, 3, 4], "");
+        patch.apply(&mut vm).unwrap();
+        assert_eq!(&vm.mem()[..7], &[1, 5, 6, 4, 6, 6, 21]);
+        assert_eq!(vm.regs(7), 16);
+
+        // a second application finds the patched words and refuses
+        let err = patch.apply(&mut vm).unwrap_err();
+        assert_eq!(err.line, 3);
+        assert_eq!(err.message, "expected 0002 0003 at 0001, found 0005 0006");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Patch::parse("reg 8 1").unwrap_err().line, 1);
+        assert_eq!(
+            Patch::parse("reg 7 40000").unwrap_err().message,
+            "invalid register value 40000"
+        );
+        assert_eq!(Patch::parse("mem 1\n").unwrap_err().line, 1);
+        assert_eq!(Patch::parse("\nasm 0\n  nope\nend").unwrap_err().line, 3);
+        assert!(Patch::parse("asm 0\n  noop\n").is_err());
+        assert!(Patch::parse("poke 1 2").is_err());
+        assert!(Patch::parse(TELEPORTER).is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+//! Walkthrough scripts: plain text files of game commands, one per line.
+//!
+//! Everything after a `#` is a comment and blank lines are skipped. Lines
+//! starting with `.` are debugger commands and run when the game reaches
+//! them; `.stop` discards the rest of the script so input comes from the
+//! terminal again.
+//!
+//! A [`Recorder`] writes the lines the game consumes back out so a session
+//! can be replayed later. Recordings start with [`RECORDING_HEADER`], and
+//! their lines are replayed exactly as written, since a typed line may
+//! contain `#`, surrounding spaces or nothing at all.
+
+use crate::vm::VM;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The built-in walkthrough of `challenge.bin` used by [`VM::auto_play`].
+pub const WALKTHROUGH: &str = include_str!("../walkthrough.txt");
+
+/// The first line of a recording made by a [`Recorder`].
+pub const RECORDING_HEADER: &str = "#!synacore-recording";
+
+/// The commands in `text`, with comments and blank lines removed, or every
+/// line untouched if `text` is a recording.
+pub fn parse_script(text: &str) -> Vec<String> {
+    let mut lines = text.lines();
+    if text.lines().next() == Some(RECORDING_HEADER) {
+        lines.next();
+        return lines.map(|line| line.to_string()).collect();
+    }
+    lines
+        .map(|line| match line.split_once('#') {
+            Some((cmd, _)) => cmd.trim(),
+            None => line.trim(),
+        })
+        .filter(|cmd| !cmd.is_empty())
+        .map(|cmd| cmd.to_string())
+        .collect()
+}
+
+/// Reads a script file from disk, see [`parse_script`].
+pub fn read_script<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    Ok(parse_script(&fs::read_to_string(path)?))
+}
+
+/// Writes every line of input the game consumes to a script.
+pub struct Recorder {
+    out: Box<dyn Write>,
+    include_debug: bool,
+    /// The line being consumed, up to the newline.
+    line: String,
+    /// Whether the header has been written.
+    started: bool,
+}
+
+impl Recorder {
+    /// Debugger command lines are left out unless `include_debug` is set.
+    pub fn new(out: Box<dyn Write>, include_debug: bool) -> Recorder {
+        Recorder {
+            out,
+            include_debug,
+            line: String::new(),
+            started: false,
+        }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P, include_debug: bool) -> io::Result<Recorder> {
+        Ok(Recorder::new(Box::new(File::create(path)?), include_debug))
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if !self.started {
+            self.out
+                .write_all(format!("{}\n", RECORDING_HEADER).as_bytes())?;
+            self.started = true;
+        }
+        self.out.write_all(format!("{}\n", line).as_bytes())?;
+        self.out.flush()
+    }
+}
+
+impl VM {
+    /// Queues every command in the script `text` as input.
+    pub fn load_script(&mut self, text: &str) {
+        for cmd in parse_script(text) {
+            self.push_input(&cmd);
+        }
+    }
+
+    /// Starts recording consumed input, replacing any recorder already
+    /// attached.
+    pub fn start_recording(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    /// Records a character consumed by `in`, writing the line out once it
+    /// is complete.
+    pub(crate) fn record_input(&mut self, c: char) -> io::Result<()> {
+        let recorder = match self.recorder.as_mut() {
+            Some(recorder) => recorder,
+            None => return Ok(()),
+        };
+        if c != '\n' {
+            recorder.line.push(c);
+            return Ok(());
+        }
+        let line = std::mem::take(&mut recorder.line);
+        recorder.write_line(&line)
+    }
+
+    /// Records a debugger command line run from the input queue.
+    pub(crate) fn record_debug(&mut self, line: &str) -> io::Result<()> {
+        match self.recorder.as_mut() {
+            Some(recorder) if recorder.include_debug => recorder.write_line(line),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let text = "# header\n\ntake tablet\n  use tablet  # read it\n.stop\n";
+        assert_eq!(
+            parse_script(text),
+            vec!["take tablet", "use tablet", ".stop"]
+        );
+        assert_eq!(parse_script(WALKTHROUGH).len(), 80);
+    }
+
+    #[test]
+    fn test_recorder() {
+        use crate::vm::{test_vm, StopReason};
+
+        let path = std::env::temp_dir().join(format!("synacore-record-{}.txt", std::process::id()));
+        // in r0; jmp 0
+        let program = vec![20, 32768, 6, 0];
+        let input = "go north\n.regs\n  say #1 \n\nlook";
+        for (include_debug, expected) in [
+            (false, "go north\n  say #1 \n\nlook\n"),
+            (true, "go north\n.regs\n  say #1 \n\nlook\n"),
+        ] {
+            let (mut vm, _) = test_vm(&program, input);
+            vm.start_recording(Recorder::create(&path, include_debug).unwrap());
+            assert_eq!(vm.run(), Ok(StopReason::EndOfInput));
+            vm.stop_recording();
+
+            // every line replays exactly as it was typed
+            let text = fs::read_to_string(&path).unwrap();
+            assert_eq!(text, format!("{}\n{}", RECORDING_HEADER, expected));
+            assert_eq!(parse_script(&text), expected.lines().collect::<Vec<_>>());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Complete VM save states and their on-disk format.
+//!
+//! A save file is little-endian throughout:
+//!
+//! ```text
+//! magic   "SYNS"
+//! version u16
+//! ip      u16
+//! flags   u16           bit 0: the next input character starts a new line
+//! mem     u32 count, then count u16 words (registers included)
+//! stack   u32 count, then count u16 words
+//! input   u32 length, then length bytes of UTF-8 queued input
+//! ```
+
+use crate::vm::{LIMIT, REGISTERS};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"SYNS";
+
+/// Version written by [`Snapshot::write_to`]. Bump when the layout changes.
+pub const VERSION: u16 = 1;
+
+/// Everything needed to resume a VM exactly where it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub mem: Vec<u16>,
+    pub stack: Vec<u16>,
+    pub ip: usize,
+    pub input_buffer: String,
+    pub at_line_start: bool,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_words<W: Write>(w: &mut W, words: &[u16]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(words.len() as u32)?;
+    for word in words {
+        w.write_u16::<LittleEndian>(*word)?;
+    }
+    Ok(())
+}
+
+fn read_words<R: Read>(r: &mut R) -> io::Result<Vec<u16>> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    let mut words = Vec::with_capacity(len.min(1 << 16));
+    for _ in 0..len {
+        words.push(r.read_u16::<LittleEndian>()?);
+    }
+    Ok(words)
+}
+
+impl Snapshot {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u16::<LittleEndian>(VERSION)?;
+        w.write_u16::<LittleEndian>(self.ip as u16)?;
+        w.write_u16::<LittleEndian>(self.at_line_start as u16)?;
+        write_words(w, &self.mem)?;
+        write_words(w, &self.stack)?;
+        w.write_u32::<LittleEndian>(self.input_buffer.len() as u32)?;
+        w.write_all(self.input_buffer.as_bytes())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Snapshot> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a save state".to_string()));
+        }
+        let version = r.read_u16::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported save state version {}",
+                version
+            )));
+        }
+
+        let ip = r.read_u16::<LittleEndian>()? as usize;
+        let flags = r.read_u16::<LittleEndian>()?;
+        if ip >= LIMIT as usize {
+            return Err(invalid_data(format!("ip {:#x} is outside memory", ip)));
+        }
+        let mem = read_words(r)?;
+        if mem.len() != LIMIT as usize + REGISTERS {
+            return Err(invalid_data(format!(
+                "expected {} words of memory and registers, found {}",
+                LIMIT as usize + REGISTERS,
+                mem.len()
+            )));
+        }
+        let stack = read_words(r)?;
+        let len = r.read_u32::<LittleEndian>()? as usize;
+        let mut input = vec![0; len];
+        r.read_exact(&mut input)?;
+        let input_buffer = String::from_utf8(input)
+            .map_err(|e| invalid_data(format!("bad input buffer: {}", e)))?;
+
+        Ok(Snapshot {
+            mem,
+            stack,
+            ip,
+            input_buffer,
+            at_line_start: flags & 1 != 0,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        Snapshot::read_from(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut mem = vec![0; LIMIT as usize + REGISTERS];
+        mem[..4].copy_from_slice(&[9, 32768, 32769, 4]);
+        let snapshot = Snapshot {
+            mem,
+            stack: vec![1, 2],
+            ip: 3,
+            input_buffer: "go north\n".to_string(),
+            at_line_start: true,
+        };
+        let mut buf = vec![];
+        snapshot.write_to(&mut buf).unwrap();
+        assert_eq!(Snapshot::read_from(&mut &buf[..]).unwrap(), snapshot);
+
+        let mut bad = buf.clone();
+        bad[4] = 99;
+        assert!(Snapshot::read_from(&mut &bad[..]).is_err());
+
+        let mut short = snapshot.clone();
+        short.mem = vec![21];
+        let mut bad = vec![];
+        short.write_to(&mut bad).unwrap();
+        let err = Snapshot::read_from(&mut &bad[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bad = buf.clone();
+        bad[6..8].copy_from_slice(&0x8000u16.to_le_bytes());
+        assert!(Snapshot::read_from(&mut &bad[..]).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! The coin puzzle: five coins go into the slots of
+//! `_ + _ * _^2 + _^3 - _ = 399`.
+//!
+//! The game only describes each coin's value as dots or a shape on one side,
+//! so the values are read from the program instead. Every coin's `use`
+//! handler hands the coin's item and its value to the routine that places
+//! it:
+//!
+//! ```text
+//! set r0 <item>      ; item record, starting with a pointer to its name
+//! set r1 <value>
+//! call <place>
+//! ```
+
+use super::read_string;
+use crate::opcode::{Instruction, Opcode};
+use crate::vm::LIMIT;
+
+/// The right hand side of the equation.
+pub const TARGET: i64 = 399;
+
+/// A coin and the number it stands for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub name: String,
+    pub value: u16,
+}
+
+/// Finds every coin's `use` handler in `mem`. Item names are only readable
+/// once the program has decrypted them, see [`super::Game::boot`].
+pub fn read_coins(mem: &[u16]) -> Vec<Coin> {
+    let mut coins: Vec<Coin> = vec![];
+    for addr in 0..(LIMIT as usize).min(mem.len()) {
+        let set_item = match Instruction::decode(mem, addr) {
+            Some(instr) if instr.opcode == Opcode::Set && instr.operands[0] == LIMIT => instr,
+            _ => continue,
+        };
+        let set_value = match Instruction::decode(mem, set_item.next()) {
+            Some(instr) if instr.opcode == Opcode::Set && instr.operands[0] == LIMIT + 1 => instr,
+            _ => continue,
+        };
+        match Instruction::decode(mem, set_value.next()) {
+            Some(instr) if instr.opcode == Opcode::Call => {}
+            _ => continue,
+        }
+
+        let (item, value) = (set_item.operands[1], set_value.operands[1]);
+        if item >= LIMIT || value >= LIMIT {
+            continue;
+        }
+        let name = match read_string(mem, mem[item as usize]) {
+            Some(name) if name.ends_with(" coin") => name,
+            _ => continue,
+        };
+        if !coins.iter().any(|c| c.name == name) {
+            coins.push(Coin { name, value });
+        }
+    }
+    coins
+}
+
+/// The left hand side of the equation with `values` in the slots.
+pub fn evaluate(values: &[i64; 5]) -> i64 {
+    let [a, b, c, d, e] = *values;
+    a + b * c.pow(2) + d.pow(3) - e
+}
+
+fn search(remaining: &mut Vec<Coin>, order: &mut Vec<Coin>) -> bool {
+    if remaining.is_empty() {
+        let values = order.iter().map(|c| c.value as i64).collect::<Vec<_>>();
+        return evaluate(&values.try_into().unwrap()) == TARGET;
+    }
+    for idx in 0..remaining.len() {
+        order.push(remaining.remove(idx));
+        if search(remaining, order) {
+            return true;
+        }
+        remaining.insert(idx, order.pop().unwrap());
+    }
+    false
+}
+
+/// The order to place `coins` in, or `None` if there aren't exactly five or
+/// no order works.
+pub fn solve(coins: &[Coin]) -> Option<Vec<Coin>> {
+    if coins.len() != 5 {
+        return None;
+    }
+    let mut order = vec![];
+    search(&mut coins.to_vec(), &mut order).then_some(order)
+}
+
+/// The game commands that place the coins in `order`.
+pub fn commands(order: &[Coin]) -> Vec<String> {
+    order.iter().map(|c| format!("use {}", c.name)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::WALKTHROUGH;
+
+    fn coin(name: &str, value: u16) -> Coin {
+        Coin {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_read_coins() {
+        let mut mem = vec![0; 0x40];
+        let mut string = |addr: usize, s: &str| {
+            mem[addr] = s.len() as u16;
+            for (idx, c) in s.chars().enumerate() {
+                mem[addr + 1 + idx] = c as u16;
+            }
+        };
+        string(0x20, "red coin");
+        string(0x30, "lantern");
+        // item records, each starting with its name
+        mem[0x10] = 0x20;
+        mem[0x14] = 0x30;
+        // set r0 item; set r1 value; call 0
+        mem[..9].copy_from_slice(&[1, LIMIT, 0x10, 1, LIMIT + 1, 2, 17, 0, 0]);
+        assert_eq!(read_coins(&mem), vec![coin("red coin", 2)]);
+
+        mem[2] = 0x14;
+        assert_eq!(read_coins(&mem), vec![]);
+    }
+
+    #[test]
+    fn test_solve() {
+        let coins = vec![
+            coin("red coin", 2),
+            coin("corroded coin", 3),
+            coin("shiny coin", 5),
+            coin("concave coin", 7),
+            coin("blue coin", 9),
+        ];
+        let order = solve(&coins).unwrap();
+        let values: Vec<u16> = order.iter().map(|c| c.value).collect();
+        assert_eq!(values, vec![9, 2, 5, 7, 3]);
+
+        // the walkthrough uses the solver's answer
+        let script = crate::script::parse_script(WALKTHROUGH);
+        let cmds = commands(&order);
+        assert!(script.windows(5).any(|w| w == cmds.as_slice()));
+
+        assert_eq!(solve(&coins[1..]), None);
+        assert_eq!(solve(&vec![coin("red coin", 1); 5]), None);
+    }
+}
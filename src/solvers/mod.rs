@@ -0,0 +1,69 @@
+//! Solvers for the puzzles in `challenge.bin`.
+//!
+//! Each solver works its answer out from the program rather than from
+//! notes taken while playing, and produces the commands (or patch) that get
+//! past the puzzle.
+
+pub mod coins;
+pub mod teleporter;
+pub mod vault;
+
+use crate::device::{BufferInput, BufferOutput};
+use crate::symbols::SymbolTable;
+use crate::vm::{StopReason, VM};
+use std::error::Error;
+
+/// A VM driven one command at a time, with its output captured.
+pub struct Game {
+    pub vm: VM,
+    output: BufferOutput,
+}
+
+impl Game {
+    /// Loads `program` and runs it up to its first prompt. `challenge.bin`
+    /// decrypts its strings during startup, so most of what the solvers read
+    /// from memory only makes sense after this.
+    pub fn boot(program: &[u16]) -> Result<Game, Box<dyn Error>> {
+        let output = BufferOutput::new();
+        let mut vm = VM::new(
+            program,
+            &SymbolTable::new(),
+            Box::new(BufferInput::new("")),
+            Box::new(output.clone()),
+        );
+        vm.set_pause_on_input(true);
+        let mut game = Game { vm, output };
+        game.resume()?;
+        Ok(game)
+    }
+
+    /// Runs until the program asks for input again, returning what it
+    /// printed on the way.
+    pub fn resume(&mut self) -> Result<String, Box<dyn Error>> {
+        self.output.clear();
+        match self.vm.run()? {
+            StopReason::NeedsInput => Ok(self.output.contents()),
+            reason => Err(format!("program stopped before asking for input: {:?}", reason).into()),
+        }
+    }
+
+    /// Types `cmd` at the prompt and returns the program's reply.
+    pub fn send(&mut self, cmd: &str) -> Result<String, Box<dyn Error>> {
+        self.vm.push_input(cmd);
+        self.resume()
+    }
+}
+
+/// The length-prefixed string at `addr`, or `None` if it runs past the end
+/// of `mem` or holds anything but printable ASCII.
+pub(crate) fn read_string(mem: &[u16], addr: u16) -> Option<String> {
+    let addr = addr as usize;
+    let len = *mem.get(addr)? as usize;
+    mem.get(addr + 1..addr + 1 + len)?
+        .iter()
+        .map(|w| match *w {
+            0x20..=0x7e => Some(*w as u8 as char),
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,259 @@
+//! The teleporter: with a non-zero energy level in r7, using it runs a
+//! confirmation routine that would take far too long in the VM, and only
+//! goes through if the routine returns the right value.
+//!
+//! The program checks the confirmation with
+//!
+//! ```text
+//! set r0 <a>
+//! set r1 <b>
+//! call <confirm>
+//! eq <reg> r0 <expected>
+//! ```
+//!
+//! where `confirm` is a modified Ackermann function, with all arithmetic
+//! modulo 32768:
+//!
+//! ```text
+//! f(0, b) = b + 1
+//! f(a, 0) = f(a - 1, r7)
+//! f(a, b) = f(a - 1, f(a, b - 1))
+//! ```
+//!
+//! [`search`] evaluates it natively for every value of r7, and [`patch`]
+//! turns a value that works into a patch file that sets r7 and skips the
+//! call. Alternatively, [`hook`] runs the routine natively in the VM, so
+//! setting r7 once the game is running is enough.
+
+use crate::hooks::Hook;
+use crate::opcode::{Instruction, Opcode};
+use crate::vm::LIMIT;
+use std::thread;
+
+/// Where the program checks the energy level, as found by [`find_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Check {
+    /// The `jt r7` in the startup self test, which fails if r7 is set.
+    pub self_test: u16,
+    /// The `set r0 <a>` before the confirmation call.
+    pub setup: u16,
+    /// The confirmation call itself.
+    pub call: u16,
+    /// The routine called.
+    pub function: u16,
+    pub a: u16,
+    pub b: u16,
+    /// What the routine has to return.
+    pub expected: u16,
+}
+
+fn decode(mem: &[u16], addr: usize, opcode: Opcode) -> Option<Instruction> {
+    Instruction::decode(mem, addr).filter(|instr| instr.opcode == opcode)
+}
+
+/// Whether the routine at `addr` looks like the confirmation: it calls
+/// itself and reads r7 within its first few instructions.
+fn is_confirmation(mem: &[u16], addr: u16) -> bool {
+    let (mut recursive, mut reads_r7) = (false, false);
+    let mut next = addr as usize;
+    for _ in 0..32 {
+        let instr = match Instruction::decode(mem, next) {
+            Some(instr) => instr,
+            None => break,
+        };
+        recursive |= instr.opcode == Opcode::Call && instr.operands[0] == addr;
+        reads_r7 |= instr.operands.contains(&(LIMIT + 7));
+        next = instr.next();
+    }
+    recursive && reads_r7
+}
+
+/// Finds the confirmation call and the self test in `mem`.
+pub fn find_check(mem: &[u16]) -> Option<Check> {
+    let r0 = LIMIT;
+    let r1 = LIMIT + 1;
+    let r7 = LIMIT + 7;
+    let end = (LIMIT as usize).min(mem.len());
+
+    let self_test = (0..end)
+        .find(|addr| decode(mem, *addr, Opcode::Jt).is_some_and(|instr| instr.operands[0] == r7))?;
+    (0..end).find_map(|addr| {
+        let set_a = decode(mem, addr, Opcode::Set).filter(|i| i.operands[0] == r0)?;
+        let set_b = decode(mem, set_a.next(), Opcode::Set).filter(|i| i.operands[0] == r1)?;
+        let call = decode(mem, set_b.next(), Opcode::Call)?;
+        let eq = decode(mem, call.next(), Opcode::Eq).filter(|i| i.operands[1] == r0)?;
+
+        let values = [
+            set_a.operands[1],
+            set_b.operands[1],
+            call.operands[0],
+            eq.operands[2],
+        ];
+        if values.iter().any(|val| *val >= LIMIT) || !is_confirmation(mem, values[2]) {
+            return None;
+        }
+        Some(Check {
+            self_test: self_test as u16,
+            setup: addr as u16,
+            call: call.addr as u16,
+            function: values[2],
+            a: values[0],
+            b: values[1],
+            expected: values[3],
+        })
+    })
+}
+
+/// The confirmation routine's result for `f(a, b)` with `r7` in register 7.
+///
+/// Every level of the recursion only ever asks the level below for single
+/// values, so each level is memoized as a table of all 32768 results,
+/// built from the table below it.
+pub fn confirm(a: u16, b: u16, r7: u16) -> u16 {
+    let size = LIMIT as usize;
+    let mut below: Vec<u16> = (1..=LIMIT).map(|b| b % LIMIT).collect();
+    let mut row = vec![0; size];
+    for level in 1..=a {
+        // the top level only needs to go as far as b
+        let len = if level == a { b as usize + 1 } else { size };
+        row[0] = below[r7 as usize];
+        for idx in 1..len {
+            row[idx] = below[row[idx - 1] as usize];
+        }
+        std::mem::swap(&mut below, &mut row);
+    }
+    below[b as usize]
+}
+
+/// Every value of r7 for which the confirmation returns `check.expected`,
+/// trying them on all available cores.
+pub fn search(check: &Check) -> Vec<u16> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut found: Vec<u16> = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads)
+            .map(|first| {
+                s.spawn(move || {
+                    (first..LIMIT as usize)
+                        .step_by(threads)
+                        .map(|r7| r7 as u16)
+                        .filter(|r7| confirm(check.a, check.b, *r7) == check.expected)
+                        .collect::<Vec<u16>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    found.sort();
+    found
+}
+
+/// A native stand-in for the confirmation routine, to register at
+/// `Check::function` with [`crate::VM::add_hook`]. Leaves r1 alone, which the
+/// program overwrites straight after the call anyway.
+pub fn hook() -> Hook {
+    Box::new(|regs| {
+        regs[0] = confirm(regs[0], regs[1], regs[7]);
+        true
+    })
+}
+
+/// A patch file, see [`crate::patch`], that sets r7 and gets past both
+/// checks. `teleporter.patch` is this for `challenge.bin`.
+pub fn patch(check: &Check, r7: u16) -> String {
+    format!(
+        "# Gets challenge.bin past the teleporter confirmation, see src/patch.rs for
+# the format. Generated by `synacore solve teleporter challenge.bin`.
+
+# the energy level the confirmation checks for
+reg 7 {r7}
+# skip the self test of r7 at startup: jt r7 -> jf r7
+mem {self_test:#06x} {jf} expect {jt}
+# the confirmation should return {expected}: set r0 {a} -> set r0 {expected}
+mem {arg:#06x} {expected} expect {a}
+# and skip the slow recursive call itself
+asm {call:#06x} expect {call_op} {function:#06x}
+    noop
+    noop
+end
+",
+        self_test = check.self_test,
+        jt = Opcode::Jt.code(),
+        jf = Opcode::Jf.code(),
+        expected = check.expected,
+        a = check.a,
+        arg = check.setup + 2,
+        call = check.call,
+        call_op = Opcode::Call.code(),
+        function = check.function,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::TELEPORTER;
+
+    fn naive(a: u16, b: u16, r7: u16) -> u16 {
+        match (a, b) {
+            (0, b) => (b + 1) % LIMIT,
+            (a, 0) => naive(a - 1, r7, r7),
+            (a, b) => naive(a - 1, naive(a, b - 1, r7), r7),
+        }
+    }
+
+    #[test]
+    fn test_confirm() {
+        for (a, b, r7) in [(0, 5, 1), (1, 3, 2), (2, 2, 3), (3, 1, 1), (2, 0, 7)] {
+            assert_eq!(confirm(a, b, r7), naive(a, b, r7), "f({}, {})", a, b);
+        }
+        assert_eq!(confirm(0, LIMIT - 1, 0), 0);
+        assert_eq!(confirm(4, 1, 25734), 6);
+    }
+
+    #[test]
+    fn test_find_check() {
+        let (r0, r1, r7) = (LIMIT, LIMIT + 1, LIMIT + 7);
+        let mut mem = [
+            &[7, r7, 0][..],      // jt r7 0
+            &[1, r0, 0x6576],     // set r0 0x6576
+            &[1, r1, 0x20],       // set r1 0x20
+            &[17, 0x1b],          // call 0x1b, not recursive
+            &[4, r1, r0, 0x7fff], // eq r1 r0 0x7fff
+            &[1, r0, 4],          // set r0 4
+            &[1, r1, 1],          // set r1 1
+            &[17, 0x1c],          // call 0x1c
+            &[4, r1, r0, 6],      // eq r1 r0 6
+            &[18],                // 0x1b: ret
+            &[1, r1, r7],         // 0x1c: set r1 r7
+            &[17, 0x1c, 18],      // call 0x1c; ret
+        ]
+        .concat();
+        mem.resize(0x30, 0);
+        let check = find_check(&mem).unwrap();
+        assert_eq!(
+            check,
+            Check {
+                self_test: 0,
+                setup: 0x0f,
+                call: 0x15,
+                function: 0x1c,
+                a: 4,
+                b: 1,
+                expected: 6,
+            }
+        );
+
+        // the check as found in challenge.bin gives the built-in patch
+        let check = Check {
+            self_test: 0x0209,
+            setup: 0x156b,
+            call: 0x1571,
+            function: 0x178b,
+            ..check
+        };
+        assert_eq!(patch(&check, 25734), TELEPORTER);
+    }
+}
@@ -0,0 +1,386 @@
+//! The vault lock: a grid of rooms with a number or an operator on each
+//! floor, and a door that opens if the orb weighs the right amount when it
+//! gets there.
+//!
+//! The orb starts out weighing the number on its pedestal. Walking onto an
+//! operator and then a number applies that operation to the weight, and the
+//! orb shatters if its weight would go negative or past 15 bits. Walking
+//! back into the antechamber resets the orb, and walking into the door's
+//! room ends the attempt either way.
+//!
+//! [`explore`] maps the grid by walking it from a snapshot and reading the
+//! room descriptions, so nothing is transcribed by hand, and [`solve`] finds
+//! the shortest walk that opens the door.
+
+use super::Game;
+use crate::script::{parse_script, WALKTHROUGH};
+use crate::vm::LIMIT;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+
+/// A way out of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Direction {
+    pub fn from_name(name: &str) -> Option<Direction> {
+        match name {
+            "north" => Some(Direction::North),
+            "east" => Some(Direction::East),
+            "south" => Some(Direction::South),
+            "west" => Some(Direction::West),
+            _ => None,
+        }
+    }
+
+    /// The command that walks this way.
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::North => "north",
+            Direction::East => "east",
+            Direction::South => "south",
+            Direction::West => "west",
+        }
+    }
+
+    /// Where this way leads from `pos`, with north as increasing `y`.
+    pub fn step(self, (x, y): (i32, i32)) -> (i32, i32) {
+        match self {
+            Direction::North => (x, y + 1),
+            Direction::East => (x + 1, y),
+            Direction::South => (x, y - 1),
+            Direction::West => (x - 1, y),
+        }
+    }
+}
+
+/// What is on a room's floor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tile {
+    Number(u16),
+    Add,
+    Sub,
+    Mul,
+}
+
+impl Tile {
+    fn parse(s: &str) -> Option<Tile> {
+        match s {
+            "+" => Some(Tile::Add),
+            "-" => Some(Tile::Sub),
+            "*" => Some(Tile::Mul),
+            _ => s.parse().ok().map(Tile::Number),
+        }
+    }
+
+    fn symbol(self) -> String {
+        match self {
+            Tile::Number(n) => n.to_string(),
+            Tile::Add => "+".to_string(),
+            Tile::Sub => "-".to_string(),
+            Tile::Mul => "*".to_string(),
+        }
+    }
+
+    /// Applies this operator to `a` and `b`, or `None` if the result would
+    /// shatter the orb by going negative or past 15 bits.
+    fn apply(self, a: u16, b: u16) -> Option<u16> {
+        let res = match self {
+            Tile::Add => a.checked_add(b),
+            Tile::Sub => a.checked_sub(b),
+            Tile::Mul => a.checked_mul(b),
+            Tile::Number(_) => Some(b),
+        };
+        res.filter(|w| *w < LIMIT)
+    }
+}
+
+/// A room of the grid as the game describes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Room {
+    pub tile: Tile,
+    pub exits: Vec<Direction>,
+    /// The weight carved into the vault door, in the door's room.
+    pub door: Option<u16>,
+}
+
+/// The text between the quotes following `marker`.
+fn quoted_after<'a>(text: &'a str, marker: &str) -> Option<&'a str> {
+    let rest = &text[text.find(marker)? + marker.len()..];
+    let start = rest.find('\'')? + 1;
+    let len = rest[start..].find('\'')?;
+    Some(&rest[start..start + len])
+}
+
+/// Parses the description the game prints on entering a room, or `None`
+/// for rooms outside the grid. The antechamber's tile is the number on the
+/// orb's pedestal.
+pub fn parse_room(text: &str) -> Option<Room> {
+    // entering the antechamber with the orb mentions it again
+    let text = &text[text.rfind("== ")?..];
+    let tile = quoted_after(text, "depicting")
+        .or_else(|| quoted_after(text, "the number"))
+        .and_then(Tile::parse)?;
+    let door = quoted_after(text, "vault; it has a large").and_then(|n| n.parse().ok());
+
+    let mut exits = vec![];
+    let mut lines = text.lines().skip_while(|line| {
+        !(line.starts_with("There ") && (line.ends_with(" exit:") || line.ends_with(" exits:")))
+    });
+    lines.next();
+    for line in lines {
+        match line.strip_prefix("- ") {
+            Some(name) => exits.extend(Direction::from_name(name)),
+            None => break,
+        }
+    }
+
+    Some(Room { tile, exits, door })
+}
+
+/// The rooms of the grid by position, with the antechamber at `(0, 0)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Grid {
+    pub rooms: BTreeMap<(i32, i32), Room>,
+}
+
+impl Grid {
+    fn start(&self) -> Option<u16> {
+        match self.rooms.get(&(0, 0))?.tile {
+            Tile::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The door's position and the weight it wants.
+    fn door(&self) -> Option<((i32, i32), u16)> {
+        self.rooms
+            .iter()
+            .find_map(|(pos, room)| room.door.map(|target| (*pos, target)))
+    }
+
+    /// The neighbours of `pos` that are part of the grid.
+    fn neighbours(&self, pos: (i32, i32)) -> impl Iterator<Item = (Direction, (i32, i32))> + '_ {
+        self.rooms[&pos]
+            .exits
+            .iter()
+            .map(move |dir| (*dir, dir.step(pos)))
+            .filter(|(_, next)| self.rooms.contains_key(next))
+    }
+}
+
+/// Plays the built-in walkthrough of `challenge.bin` up to picking up the
+/// orb, applying the built-in patch for the teleporter on the way.
+pub fn antechamber(program: &[u16]) -> Result<Game, Box<dyn Error>> {
+    let mut game = Game::boot(program)?;
+    game.vm.patch()?;
+    for cmd in parse_script(WALKTHROUGH) {
+        game.send(&cmd)?;
+        if cmd == "take orb" {
+            return Ok(game);
+        }
+    }
+    Err("the walkthrough never takes the orb".into())
+}
+
+/// Maps the grid around `game`, which must be in the antechamber holding
+/// the orb. Every room is visited from a snapshot of that state, which
+/// `game` is left in afterwards.
+pub fn explore(game: &mut Game) -> Result<Grid, Box<dyn Error>> {
+    let snapshot = game.vm.snapshot();
+    let start = parse_room(&game.send("look")?).ok_or("not in the vault antechamber")?;
+
+    let mut grid = Grid::default();
+    let mut seen = HashSet::from([(0, 0)]);
+    let mut queue = VecDeque::from([((0, 0), vec![])]);
+    grid.rooms.insert((0, 0), start);
+
+    while let Some((pos, path)) = queue.pop_front() {
+        for dir in grid.rooms[&pos].exits.clone() {
+            let next = dir.step(pos);
+            if !seen.insert(next) {
+                continue;
+            }
+            game.vm.restore(&snapshot);
+            let mut path: Vec<Direction> = path.clone();
+            path.push(dir);
+            let mut text = String::new();
+            for dir in &path {
+                text = game.send(dir.name())?;
+            }
+            if let Some(room) = parse_room(&text) {
+                grid.rooms.insert(next, room);
+                queue.push_back((next, path));
+            }
+        }
+    }
+
+    game.vm.restore(&snapshot);
+    Ok(grid)
+}
+
+/// The shortest walk from the antechamber that reaches the door with the
+/// orb at the door's weight, or `None` if there isn't one.
+pub fn solve(grid: &Grid) -> Option<Vec<Direction>> {
+    let start = grid.start()?;
+    let (door, target) = grid.door()?;
+
+    // position, weight and the operator waiting for a number
+    type State = ((i32, i32), u16, Option<Tile>);
+    let initial: State = ((0, 0), start, None);
+    let mut came_from: HashMap<State, (State, Direction)> = HashMap::new();
+    let mut seen = HashSet::from([initial]);
+    let mut queue = VecDeque::from([initial]);
+
+    while let Some(state) = queue.pop_front() {
+        let (pos, weight, op) = state;
+        for (dir, next) in grid.neighbours(pos) {
+            if next == (0, 0) {
+                continue;
+            }
+            let next_state = match (grid.rooms[&next].tile, op) {
+                (Tile::Number(n), Some(op)) => match op.apply(weight, n) {
+                    Some(weight) => (next, weight, None),
+                    None => continue,
+                },
+                (Tile::Number(_), None) => continue,
+                (tile, _) => (next, weight, Some(tile)),
+            };
+            if !seen.insert(next_state) {
+                continue;
+            }
+            came_from.insert(next_state, (state, dir));
+
+            if next == door {
+                if next_state.1 == target && next_state.2.is_none() {
+                    let mut moves = vec![];
+                    let mut state = next_state;
+                    while let Some((prev, dir)) = came_from.get(&state) {
+                        moves.push(*dir);
+                        state = *prev;
+                    }
+                    moves.reverse();
+                    return Some(moves);
+                }
+                continue;
+            }
+            queue.push_back(next_state);
+        }
+    }
+    None
+}
+
+/// The arithmetic a walk spells out, such as `22 + 4 - 11 = 15`.
+pub fn expression(grid: &Grid, moves: &[Direction]) -> String {
+    let mut pos = (0, 0);
+    let mut weight = grid.start().unwrap_or(0);
+    let mut op: Option<Tile> = None;
+    let mut out = weight.to_string();
+    for dir in moves {
+        pos = dir.step(pos);
+        let tile = match grid.rooms.get(&pos) {
+            Some(room) => room.tile,
+            None => break,
+        };
+        match (tile, op) {
+            (Tile::Number(n), Some(pending)) => {
+                weight = pending.apply(weight, n).unwrap_or(0);
+                op = None;
+            }
+            (Tile::Number(_), None) => {}
+            (tile, _) => op = Some(tile),
+        }
+        out.push(' ');
+        out.push_str(&tile.symbol());
+    }
+    format!("{} = {}", out, weight)
+}
+
+/// The game commands for `moves`.
+pub fn commands(moves: &[Direction]) -> Vec<String> {
+    moves.iter().map(|dir| dir.name().to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_room() {
+        let text = "\n\nAs you enter the room, the orb briefly flashes red.\n\n\
+            == Vault Door ==\nYou stand before the door to the vault; it has a large '30' carved into it.\n\n\
+            The floor of this room is a large mosaic depicting the number '1'.\n\n\
+            There are 3 exits:\n- south\n- west\n- vault\n\nWhat do you do?\n";
+        let room = parse_room(text).unwrap();
+        assert_eq!(room.tile, Tile::Number(1));
+        assert_eq!(room.door, Some(30));
+        assert_eq!(room.exits, vec![Direction::South, Direction::West]);
+
+        let text = "== Vault Antechamber ==\nYou notice the number '22' is carved into the orb's pedestal.\n\n\
+            Things of interest here:\n- orb\n\nThere are 3 exits:\n- north\n- east\n- south\n";
+        let room = parse_room(text).unwrap();
+        assert_eq!(room.tile, Tile::Number(22));
+        assert_eq!(room.exits.len(), 3);
+
+        let text = "== Vault Lock ==\nThe floor of this room is a large mosaic depicting a '*' symbol.\n\n\
+            There is 1 exit:\n- west\n";
+        assert_eq!(parse_room(text).unwrap().tile, Tile::Mul);
+        assert_eq!(
+            parse_room("== Tropical Cave ==\nThere are 2 exits:\n- north\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_solve() {
+        // the grid from challenge.bin, antechamber bottom left, rows from the top
+        let tiles = [
+            ["*", "8", "-", "1"],
+            ["4", "*", "11", "*"],
+            ["+", "4", "-", "18"],
+            ["22", "-", "9", "*"],
+        ];
+        let mut grid = Grid::default();
+        for (row, tiles) in tiles.iter().enumerate() {
+            for (col, tile) in tiles.iter().enumerate() {
+                let pos = (col as i32, 3 - row as i32);
+                let exits = [
+                    Direction::North,
+                    Direction::East,
+                    Direction::South,
+                    Direction::West,
+                ];
+                let exits = exits
+                    .into_iter()
+                    .filter(|dir| {
+                        let (x, y) = dir.step(pos);
+                        (0..4).contains(&x) && (0..4).contains(&y)
+                    })
+                    .collect();
+                let door = (pos == (3, 3)).then_some(30);
+                let tile = Tile::parse(tile).unwrap();
+                grid.rooms.insert(pos, Room { tile, exits, door });
+            }
+        }
+
+        let moves = solve(&grid).unwrap();
+        assert_eq!(moves.len(), 12);
+        assert!(expression(&grid, &moves).ends_with(" 1 = 30"));
+
+        // the walkthrough uses the solver's answer
+        let script = parse_script(WALKTHROUGH);
+        let cmds = commands(&moves);
+        assert!(script.windows(cmds.len()).any(|w| w == cmds.as_slice()));
+
+        // two numbers in a row never change the weight
+        grid.rooms.retain(|pos, _| pos.1 == 0 || *pos == (0, 1));
+        grid.rooms.get_mut(&(0, 1)).unwrap().tile = Tile::Number(1);
+        grid.rooms.get_mut(&(0, 1)).unwrap().door = Some(22);
+        assert_eq!(solve(&grid), None);
+    }
+}
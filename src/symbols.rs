@@ -0,0 +1,159 @@
+//! Symbol tables mapping addresses to human readable names.
+
+use crate::vm::LIMIT;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+
+/// Names for addresses in the loaded image, as read from a `.sym` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    names: HashMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    /// The name at exactly `addr`, if there is one.
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(|s| s.as_str())
+    }
+
+    /// Resolves a name in the table, or else a hex address, to an address in
+    /// memory.
+    pub fn resolve(&self, s: &str) -> Option<u16> {
+        self.address_of(s)
+            .or_else(|| u16::from_str_radix(s, 16).ok())
+            .filter(|addr| *addr < LIMIT)
+    }
+
+    /// Reverse lookup from name to address.
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.names
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// The closest symbol at or below `addr`.
+    pub fn nearest(&self, addr: u16) -> Option<(u16, &str)> {
+        self.names
+            .iter()
+            .filter(|(a, _)| **a <= addr)
+            .max_by_key(|(a, _)| **a)
+            .map(|(a, name)| (*a, name.as_str()))
+    }
+
+    /// Renders `addr` relative to the nearest symbol, like `fib+0x8`, or as
+    /// plain hex when no symbol precedes it.
+    pub fn symbolize(&self, addr: u16) -> String {
+        match self.nearest(addr) {
+            Some((a, name)) if a == addr => name.to_string(),
+            Some((a, name)) => format!("{}+0x{:x}", name, addr - a),
+            None => format!("{:04x}", addr),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// All symbols ordered by address.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        let mut entries: Vec<(u16, &str)> = self
+            .names
+            .iter()
+            .map(|(addr, name)| (*addr, name.as_str()))
+            .collect();
+        entries.sort();
+        entries.into_iter()
+    }
+}
+
+/// The name given to a function nobody has named yet, like `sub_05b2`.
+pub fn default_name(addr: u16) -> String {
+    format!("sub_{:04x}", addr)
+}
+
+/// Renders `table` in the format [`parse_symbols`] reads, ordered by address.
+pub fn format_symbols(table: &SymbolTable) -> String {
+    table
+        .iter()
+        .map(|(addr, name)| format!("{:04x},{}\n", addr, name))
+        .collect()
+}
+
+/// Parses symbols in the `addr,name` format, one per line with `addr` in hex.
+pub fn parse_symbols(text: &str) -> io::Result<SymbolTable> {
+    let mut table = SymbolTable::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let parts = line.split(',').collect::<Vec<&str>>();
+        let addr = u16::from_str_radix(parts[0].trim(), 16).ok();
+        match (addr, parts.get(1)) {
+            (Some(addr), Some(name)) => table.insert(addr, name.trim()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid symbol line: {}", line),
+                ))
+            }
+        }
+    }
+
+    Ok(table)
+}
+
+/// Reads a symbol file from disk, see [`parse_symbols`].
+pub fn read_symbols<P: AsRef<Path>>(filename: P) -> io::Result<SymbolTable> {
+    let file = File::open(filename)?;
+    let mut text = String::new();
+    BufReader::new(file).read_to_string(&mut text)?;
+    parse_symbols(&text)
+}
+
+/// Writes a symbol file to disk, see [`format_symbols`].
+pub fn write_symbols<P: AsRef<Path>>(filename: P, table: &SymbolTable) -> io::Result<()> {
+    let mut file = File::create(filename)?;
+    file.write_all(format_symbols(table).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_symbols() {
+        let table = parse_symbols("05b2,fetch_decryption_key\n178b,fib\n\n").unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(0x178b), Some("fib"));
+        assert_eq!(table.address_of("fetch_decryption_key"), Some(0x05b2));
+        assert!(parse_symbols("zzzz,nope").is_err());
+
+        assert_eq!(table.symbolize(0x178b), "fib");
+        assert_eq!(table.symbolize(0x1793), "fib+0x8");
+        assert_eq!(table.symbolize(0x0010), "0010");
+
+        let text = format_symbols(&table);
+        assert_eq!(text, "05b2,fetch_decryption_key\n178b,fib\n");
+        assert_eq!(parse_symbols(&text).unwrap(), table);
+        assert_eq!(default_name(0x05b2), "sub_05b2");
+    }
+}
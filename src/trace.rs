@@ -0,0 +1,741 @@
+//! Structured execution traces.
+//!
+//! A [`Tracer`] attached to the VM writes one record per executed
+//! instruction: its address, opcode, raw and resolved operands, the register
+//! file and stack depth before it ran, the symbol it belongs to and every
+//! memory or register write it made.
+//!
+//! Records are either JSON, one object per line:
+//!
+//! ```text
+//! {"ip":1458,"op":"add","operands":[32768,32769,4],"values":[2,3,4],"regs":[2,3,0,0,0,0,0,0],"stack":1,"symbol":"fib","writes":[[32768,7]]}
+//! ```
+//!
+//! or a compact little-endian binary stream headed by `"SYNT"` and a `u16`
+//! version, each record laid out as:
+//!
+//! ```text
+//! ip        u16
+//! opcode    u16
+//! operands  arity x u16
+//! values    arity x u16
+//! regs      8 x u16
+//! stack     u32 depth
+//! symbol    u16 length, then length bytes of UTF-8; empty if there is none
+//! writes    u16 count, then count pairs of u16 address and value
+//! ```
+
+use crate::opcode::Opcode;
+use crate::symbols::SymbolTable;
+use crate::vm::{LIMIT, REGISTERS, VM};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufWriter};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"SYNT";
+
+/// Version of the binary trace format. Bump when the layout changes.
+pub const VERSION: u16 = 1;
+
+/// How records are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Json,
+    Binary,
+}
+
+/// Limits which instructions are traced. An empty filter traces everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only instructions in the inclusive range `start..=end`.
+    pub range: Option<(u16, u16)>,
+    /// Only instructions whose symbol is one of these.
+    pub symbols: Vec<String>,
+}
+
+impl TraceFilter {
+    /// Parses an inclusive range written `addr` or `start-end`, each a name
+    /// from `symbols` or a hex address.
+    pub fn parse_range(s: &str, symbols: &SymbolTable) -> Option<(u16, u16)> {
+        match s.split_once('-') {
+            Some((start, end)) => {
+                let start = symbols.resolve(start)?;
+                let end = symbols.resolve(end)?;
+                Some((start, end)).filter(|_| start <= end)
+            }
+            None => symbols.resolve(s).map(|addr| (addr, addr)),
+        }
+    }
+
+    pub fn matches(&self, ip: u16, symbol: Option<&str>) -> bool {
+        let in_range = match self.range {
+            Some((start, end)) => (start..=end).contains(&ip),
+            None => true,
+        };
+        let in_symbol = self.symbols.is_empty()
+            || symbol.is_some_and(|sym| self.symbols.iter().any(|s| s == sym));
+        in_range && in_symbol
+    }
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub ip: u16,
+    pub opcode: Opcode,
+    pub operands: Vec<u16>,
+    /// Operands with registers replaced by their contents.
+    pub values: Vec<u16>,
+    pub regs: [u16; REGISTERS],
+    pub stack_depth: usize,
+    /// The nearest symbol at or below `ip`.
+    pub symbol: Option<String>,
+    /// Memory and register writes, as address and new value.
+    pub writes: Vec<(u16, u16)>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_list(words: &[u16]) -> String {
+    let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+    format!("[{}]", words.join(","))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl TraceRecord {
+    /// Renders the record as a single line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        let writes: Vec<String> = self
+            .writes
+            .iter()
+            .map(|(addr, val)| format!("[{},{}]", addr, val))
+            .collect();
+        format!(
+            "{{\"ip\":{},\"op\":{},\"operands\":{},\"values\":{},\"regs\":{},\"stack\":{},\"symbol\":{},\"writes\":[{}]}}",
+            self.ip,
+            json_string(self.opcode.name()),
+            json_list(&self.operands),
+            json_list(&self.values),
+            json_list(&self.regs),
+            self.stack_depth,
+            self.symbol.as_deref().map_or("null".to_string(), json_string),
+            writes.join(",")
+        )
+    }
+
+    /// Parses a line written by [`TraceRecord::to_json`].
+    pub fn from_json(line: &str) -> io::Result<TraceRecord> {
+        TraceRecord::parse_json(line).map_err(invalid_data)
+    }
+
+    fn parse_json(line: &str) -> Result<TraceRecord, String> {
+        let json = Json::parse(line)?;
+        let name = match json.field("op")? {
+            Json::Str(name) => name,
+            _ => return Err("expected a mnemonic".to_string()),
+        };
+        let opcode = Opcode::from_name(name).ok_or(format!("unknown mnemonic {}", name))?;
+
+        let mut regs = [0; REGISTERS];
+        let words = json.field("regs")?.words()?;
+        if words.len() != REGISTERS {
+            return Err(format!("expected {} registers", REGISTERS));
+        }
+        regs.copy_from_slice(&words);
+
+        let symbol = match json.field("symbol")? {
+            Json::Str(symbol) => Some(symbol.clone()),
+            Json::Null => None,
+            _ => return Err("expected a symbol".to_string()),
+        };
+
+        let mut writes = vec![];
+        for write in json.field("writes")?.list()? {
+            match write.words()?[..] {
+                [addr, val] => writes.push((addr, val)),
+                _ => return Err("expected an address and value".to_string()),
+            }
+        }
+
+        let record = TraceRecord {
+            ip: json.field("ip")?.word()?,
+            opcode,
+            operands: json.field("operands")?.words()?,
+            values: json.field("values")?.words()?,
+            regs,
+            stack_depth: json.field("stack")?.num()? as usize,
+            symbol,
+            writes,
+        };
+        record.check()?;
+        Ok(record)
+    }
+
+    /// Rejects records no VM could have written, which would otherwise trip
+    /// up anything indexing operands or registers by them.
+    fn check(&self) -> Result<(), String> {
+        let arity = self.opcode.arity();
+        if self.operands.len() != arity || self.values.len() != arity {
+            return Err(format!("{} takes {} operands", self.opcode.name(), arity));
+        }
+        match self
+            .writes
+            .iter()
+            .find(|(addr, _)| *addr >= LIMIT + REGISTERS as u16)
+        {
+            Some((addr, _)) => Err(format!("write to {} is out of range", addr)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u16::<LittleEndian>(self.ip)?;
+        w.write_u16::<LittleEndian>(self.opcode.code())?;
+        for word in self.operands.iter().chain(&self.values).chain(&self.regs) {
+            w.write_u16::<LittleEndian>(*word)?;
+        }
+        w.write_u32::<LittleEndian>(self.stack_depth as u32)?;
+        let symbol = self.symbol.as_deref().unwrap_or("");
+        w.write_u16::<LittleEndian>(symbol.len() as u16)?;
+        w.write_all(symbol.as_bytes())?;
+        w.write_u16::<LittleEndian>(self.writes.len() as u16)?;
+        for (addr, val) in &self.writes {
+            w.write_u16::<LittleEndian>(*addr)?;
+            w.write_u16::<LittleEndian>(*val)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next binary record, or `None` at the end of the stream.
+    pub fn read_binary<R: Read>(r: &mut R) -> io::Result<Option<TraceRecord>> {
+        let ip = match r.read_u16::<LittleEndian>() {
+            Ok(ip) => ip,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let code = r.read_u16::<LittleEndian>()?;
+        let opcode =
+            Opcode::from_u16(code).ok_or_else(|| invalid_data(format!("bad opcode {}", code)))?;
+
+        let mut words =
+            |n| -> io::Result<Vec<u16>> { (0..n).map(|_| r.read_u16::<LittleEndian>()).collect() };
+        let operands = words(opcode.arity())?;
+        let values = words(opcode.arity())?;
+        let mut regs = [0; REGISTERS];
+        regs.copy_from_slice(&words(REGISTERS)?);
+
+        let stack_depth = r.read_u32::<LittleEndian>()? as usize;
+        let len = r.read_u16::<LittleEndian>()? as usize;
+        let mut symbol = vec![0; len];
+        r.read_exact(&mut symbol)?;
+        let symbol =
+            String::from_utf8(symbol).map_err(|e| invalid_data(format!("bad symbol: {}", e)))?;
+
+        let count = r.read_u16::<LittleEndian>()?;
+        let mut writes = vec![];
+        for _ in 0..count {
+            let addr = r.read_u16::<LittleEndian>()?;
+            let val = r.read_u16::<LittleEndian>()?;
+            writes.push((addr, val));
+        }
+
+        let record = TraceRecord {
+            ip,
+            opcode,
+            operands,
+            values,
+            regs,
+            stack_depth,
+            symbol: Some(symbol).filter(|s| !s.is_empty()),
+            writes,
+        };
+        record.check().map_err(invalid_data)?;
+        Ok(Some(record))
+    }
+}
+
+/// The subset of JSON used by trace records.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Num(u64),
+    Str(String),
+    List(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn skip_ws(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), String> {
+        self.skip_ws();
+        match self.chars.next() {
+            Some(c) if c == want => Ok(()),
+            Some(c) => Err(format!("expected '{}', found '{}'", want, c)),
+            None => Err(format!("expected '{}', found end of line", want)),
+        }
+    }
+
+    /// Parses comma separated items up to `close`, the opening bracket
+    /// already consumed.
+    fn items<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut items = vec![];
+        self.skip_ws();
+        if self.chars.next_if_eq(&close).is_some() {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_ws();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(c) if c == close => return Ok(items),
+                _ => return Err(format!("expected ',' or '{}'", close)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('u') => {
+                        let hex: String = self.chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        s.push(c.ok_or(format!("bad escape \\u{}", hex))?);
+                    }
+                    Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                    _ => return Err("bad escape".to_string()),
+                },
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_ws();
+        match self.chars.peek() {
+            Some('"') => self.string().map(Json::Str),
+            Some('[') => {
+                self.chars.next();
+                self.items(']', |p| p.value()).map(Json::List)
+            }
+            Some('{') => {
+                self.chars.next();
+                let fields = self.items('}', |p| {
+                    let key = p.string()?;
+                    p.expect(':')?;
+                    Ok((key, p.value()?))
+                })?;
+                Ok(Json::Object(fields))
+            }
+            Some('n') => {
+                let word: String = self.chars.by_ref().take(4).collect();
+                Some(Json::Null)
+                    .filter(|_| word == "null")
+                    .ok_or(format!("unexpected {}", word))
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut n = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+                    n.push(c);
+                }
+                n.parse()
+                    .map(Json::Num)
+                    .map_err(|_| format!("bad number {}", n))
+            }
+            Some(c) => Err(format!("unexpected '{}'", c)),
+            None => Err("unexpected end of line".to_string()),
+        }
+    }
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            chars: text.chars().peekable(),
+        };
+        let value = parser.value()?;
+        parser.skip_ws();
+        match parser.chars.next() {
+            Some(c) => Err(format!("trailing '{}'", c)),
+            None => Ok(value),
+        }
+    }
+
+    fn field(&self, name: &str) -> Result<&Json, String> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or(format!("missing field {}", name)),
+            _ => Err("expected an object".to_string()),
+        }
+    }
+
+    fn num(&self) -> Result<u64, String> {
+        match self {
+            Json::Num(n) => Ok(*n),
+            _ => Err("expected a number".to_string()),
+        }
+    }
+
+    fn word(&self) -> Result<u16, String> {
+        u16::try_from(self.num()?).map_err(|_| "number out of range".to_string())
+    }
+
+    fn list(&self) -> Result<&[Json], String> {
+        match self {
+            Json::List(items) => Ok(items),
+            _ => Err("expected a list".to_string()),
+        }
+    }
+
+    fn words(&self) -> Result<Vec<u16>, String> {
+        self.list()?.iter().map(|w| w.word()).collect()
+    }
+}
+
+/// Reads the header of a binary trace, failing if it is not one.
+pub fn read_binary_header<R: Read>(r: &mut R) -> io::Result<()> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a binary trace".to_string()));
+    }
+    let version = r.read_u16::<LittleEndian>()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported trace version {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+/// Reads a whole trace file in either format.
+pub fn read_trace<P: AsRef<Path>>(path: P) -> io::Result<Vec<TraceRecord>> {
+    let data = fs::read(path)?;
+    let mut records = vec![];
+
+    if data.starts_with(MAGIC) {
+        let mut r = &data[..];
+        read_binary_header(&mut r)?;
+        while let Some(record) = TraceRecord::read_binary(&mut r)? {
+            records.push(record);
+        }
+    } else {
+        let text =
+            String::from_utf8(data).map_err(|e| invalid_data(format!("bad trace: {}", e)))?;
+        for (idx, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = TraceRecord::parse_json(line)
+                .map_err(|e| invalid_data(format!("line {}: {}", idx + 1, e)))?;
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+/// Writes trace records for the VM to a file or any other writer.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    /// The record for the instruction being executed, if it passed the filter.
+    pending: Option<TraceRecord>,
+}
+
+impl Tracer {
+    pub fn new(
+        mut out: Box<dyn Write>,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> io::Result<Tracer> {
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+            out.write_u16::<LittleEndian>(VERSION)?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+            pending: None,
+        })
+    }
+
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> io::Result<Tracer> {
+        let file = BufWriter::new(File::create(path)?);
+        Tracer::new(Box::new(file), format, filter)
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn filter(&self) -> &TraceFilter {
+        &self.filter
+    }
+
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Json => writeln!(self.out, "{}", record.to_json()),
+            TraceFormat::Binary => record.write_binary(&mut self.out),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl VM {
+    /// Starts writing a record for every instruction executed from now on,
+    /// replacing any tracer already attached.
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Detaches the tracer, flushing what it has written.
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(mut tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    /// Starts the record for the instruction about to execute at `ip`.
+    pub(crate) fn trace_begin(&mut self, opcode: Opcode) {
+        let tracer = match self.tracer.as_mut() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        tracer.pending = None;
+
+        let ip = self.ip as u16;
+        let symbol = self.symbols.nearest(ip).map(|(_, name)| name);
+        if !tracer.filter.matches(ip, symbol) {
+            return;
+        }
+
+        let operands = self.mem[self.ip + 1..self.ip + opcode.size()].to_vec();
+        let values = operands
+            .iter()
+            .map(|op| match *op {
+                op if (LIMIT..LIMIT + REGISTERS as u16).contains(&op) => self.mem[op as usize],
+                op => op,
+            })
+            .collect();
+        let mut regs = [0; REGISTERS];
+        regs.copy_from_slice(&self.mem[LIMIT as usize..LIMIT as usize + REGISTERS]);
+
+        tracer.pending = Some(TraceRecord {
+            ip,
+            opcode,
+            operands,
+            values,
+            regs,
+            stack_depth: self.stack.len(),
+            symbol: symbol.map(|s| s.to_string()),
+            writes: vec![],
+        });
+    }
+
+    pub(crate) fn trace_write(&mut self, addr: u16, val: u16) {
+        if let Some(record) = self.tracer.as_mut().and_then(|t| t.pending.as_mut()) {
+            record.writes.push((addr, val));
+        }
+    }
+
+    /// Writes out the record for the instruction that just completed.
+    pub(crate) fn trace_end(&mut self) -> io::Result<()> {
+        match self.tracer.as_mut() {
+            Some(tracer) => match tracer.pending.take() {
+                Some(record) => tracer.write(&record),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolTable;
+    use crate::vm::{test_vm, test_vm_with_symbols, StopReason};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer whose contents stay readable after the tracer owns it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn traced_vm(format: TraceFormat, filter: TraceFilter) -> (VM, SharedBuf) {
+        // set r0 4; call 0x0006; halt; add r1 r0 1; ret
+        let program = vec![1, 32768, 4, 17, 0x0006, 0, 9, 32769, 32768, 1, 18];
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x0006, "inc");
+        let (mut vm, _) = test_vm_with_symbols(&program, &symbols, "");
+        let buf = SharedBuf::default();
+        vm.start_trace(Tracer::new(Box::new(buf.clone()), format, filter).unwrap());
+        assert_eq!(vm.run(), Ok(StopReason::Halted));
+        vm.stop_trace().unwrap();
+        (vm, buf)
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let filter = TraceFilter {
+            range: None,
+            symbols: vec!["inc".to_string()],
+        };
+        let (_, buf) = traced_vm(TraceFormat::Json, filter);
+        let expected = "{\"ip\":6,\"op\":\"add\",\"operands\":[32769,32768,1],\"values\":[0,4,1],\"regs\":[4,0,0,0,0,0,0,0],\"stack\":1,\"symbol\":\"inc\",\"writes\":[[32769,5]]}
+{\"ip\":10,\"op\":\"ret\",\"operands\":[],\"values\":[],\"regs\":[4,5,0,0,0,0,0,0],\"stack\":1,\"symbol\":\"inc\",\"writes\":[]}
+";
+        assert_eq!(String::from_utf8(buf.0.borrow().clone()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x0006, "inc");
+        assert_eq!(TraceFilter::parse_range("inc", &symbols), Some((6, 6)));
+        assert_eq!(TraceFilter::parse_range("2-inc", &symbols), Some((2, 6)));
+        assert_eq!(TraceFilter::parse_range("inc-2", &symbols), None);
+        assert_eq!(TraceFilter::parse_range("8000", &symbols), None);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let (_, buf) = traced_vm(TraceFormat::Json, TraceFilter::default());
+        let text = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        for line in text.lines() {
+            assert_eq!(TraceRecord::from_json(line).unwrap().to_json(), line);
+        }
+        assert!(TraceRecord::from_json("{\"ip\":1}").is_err());
+
+        let line = text.lines().nth(1).unwrap();
+        assert!(line.contains("\"op\":\"call\""));
+        let bad = line.replace("\"values\":[6]", "\"values\":[]");
+        assert_eq!(
+            TraceRecord::from_json(&bad).unwrap_err().to_string(),
+            "call takes 1 operands"
+        );
+        let bad = text
+            .lines()
+            .next()
+            .unwrap()
+            .replace("[[32768,4]]", "[[40000,4]]");
+        assert_eq!(
+            TraceRecord::from_json(&bad).unwrap_err().to_string(),
+            "write to 40000 is out of range"
+        );
+    }
+
+    #[test]
+    fn test_trace_final_ret() {
+        let (mut vm, _) = test_vm(&[18], "");
+        let buf = SharedBuf::default();
+        vm.start_trace(
+            Tracer::new(
+                Box::new(buf.clone()),
+                TraceFormat::Json,
+                TraceFilter::default(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(vm.run(), Ok(StopReason::Returned));
+        vm.stop_trace().unwrap();
+        let text = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        assert_eq!(
+            TraceRecord::from_json(text.trim_end()).unwrap().opcode,
+            Opcode::Ret
+        );
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let (_, buf) = traced_vm(TraceFormat::Binary, TraceFilter::default());
+        let data = buf.0.borrow().clone();
+        let mut r = &data[..];
+        read_binary_header(&mut r).unwrap();
+
+        let mut records = vec![];
+        while let Some(record) = TraceRecord::read_binary(&mut r).unwrap() {
+            records.push(record);
+        }
+        let ips: Vec<u16> = records.iter().map(|r| r.ip).collect();
+        assert_eq!(ips, vec![0, 3, 6, 10, 5]);
+        // the instruction that stops the machine is traced too
+        assert_eq!(records[4].opcode, Opcode::Halt);
+        assert_eq!(records[0].writes, vec![(32768, 4)]);
+        assert_eq!(records[1].symbol, None);
+        assert_eq!(records[2].symbol.as_deref(), Some("inc"));
+
+        // a write past the registers is rejected, not passed on
+        let mut record = records[0].clone();
+        record.writes = vec![(40000, 4)];
+        let mut data = vec![];
+        record.write_binary(&mut data).unwrap();
+        let err = TraceRecord::read_binary(&mut &data[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
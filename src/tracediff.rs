@@ -0,0 +1,243 @@
+//! Comparing two execution traces.
+//!
+//! Traces are aligned record by record from the start, so they should be
+//! recorded from the same starting point with the same filter. The first
+//! record that differs, other than in its symbol, is the divergence point.
+
+use crate::disasm::format_instruction;
+use crate::opcode::{Instruction, Opcode};
+use crate::symbols::SymbolTable;
+use crate::trace::TraceRecord;
+use crate::vm::{LIMIT, REGISTERS};
+use std::collections::BTreeMap;
+
+/// Where two traces stop agreeing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the first differing record.
+    pub index: usize,
+    /// The differing records, `None` where a trace has already ended.
+    pub a: Option<TraceRecord>,
+    pub b: Option<TraceRecord>,
+    /// Registers that differ just before the differing records run, as
+    /// register number and the value in each trace.
+    pub registers: Vec<(usize, u16, u16)>,
+    /// Memory and registers that differ once the differing records have run,
+    /// as far as the writes recorded in each trace tell.
+    pub memory: Vec<(u16, Option<u16>, Option<u16>)>,
+    /// Calls active at the divergence, outermost first, as call site and
+    /// target, reconstructed from the `call` and `ret` records.
+    pub call_stack: Vec<(u16, u16)>,
+}
+
+fn same(a: &TraceRecord, b: &TraceRecord) -> bool {
+    a.ip == b.ip
+        && a.opcode == b.opcode
+        && a.operands == b.operands
+        && a.values == b.values
+        && a.regs == b.regs
+        && a.stack_depth == b.stack_depth
+        && a.writes == b.writes
+}
+
+/// Memory and registers written by a trace, and the registers after its
+/// latest record.
+#[derive(Default)]
+struct State {
+    memory: BTreeMap<u16, u16>,
+    regs: [u16; REGISTERS],
+}
+
+impl State {
+    fn apply(&mut self, record: &TraceRecord) {
+        self.regs = record.regs;
+        for (addr, val) in &record.writes {
+            if *addr >= LIMIT {
+                self.regs[(*addr - LIMIT) as usize] = *val;
+            }
+            self.memory.insert(*addr, *val);
+        }
+    }
+
+    /// Registers just before `record`, or after the last record if the
+    /// trace has ended.
+    fn regs_before(&self, record: Option<&TraceRecord>) -> [u16; REGISTERS] {
+        record.map_or(self.regs, |r| r.regs)
+    }
+}
+
+/// Finds the first point where `a` and `b` diverge, or `None` if they are
+/// identical.
+pub fn diff(a: &[TraceRecord], b: &[TraceRecord]) -> Option<Divergence> {
+    let mut state_a = State::default();
+    let mut state_b = State::default();
+    let mut call_stack = vec![];
+
+    let mut index = 0;
+    loop {
+        match (a.get(index), b.get(index)) {
+            (None, None) => return None,
+            (Some(ra), Some(rb)) if same(ra, rb) => {
+                state_a.apply(ra);
+                state_b.apply(rb);
+                match ra.opcode {
+                    Opcode::Call => call_stack.push((ra.ip, ra.values[0])),
+                    Opcode::Ret => {
+                        call_stack.pop();
+                    }
+                    _ => {}
+                }
+                index += 1;
+            }
+            (ra, rb) => {
+                let regs_a = state_a.regs_before(ra);
+                let regs_b = state_b.regs_before(rb);
+                if let Some(ra) = ra {
+                    state_a.apply(ra);
+                }
+                if let Some(rb) = rb {
+                    state_b.apply(rb);
+                }
+
+                let registers = (0..REGISTERS)
+                    .filter(|r| regs_a[*r] != regs_b[*r])
+                    .map(|r| (r, regs_a[r], regs_b[r]))
+                    .collect();
+                let mut addrs: Vec<u16> = state_a.memory.keys().copied().collect();
+                addrs.extend(state_b.memory.keys());
+                addrs.sort();
+                addrs.dedup();
+                let memory = addrs
+                    .into_iter()
+                    .map(|addr| {
+                        let va = state_a.memory.get(&addr).copied();
+                        let vb = state_b.memory.get(&addr).copied();
+                        (addr, va, vb)
+                    })
+                    .filter(|(_, va, vb)| va != vb)
+                    .collect();
+
+                return Some(Divergence {
+                    index,
+                    a: ra.cloned(),
+                    b: rb.cloned(),
+                    registers,
+                    memory,
+                    call_stack,
+                });
+            }
+        }
+    }
+}
+
+fn describe(record: &Option<TraceRecord>, symbols: &SymbolTable) -> String {
+    match record {
+        Some(record) => {
+            let instr = Instruction {
+                addr: record.ip as usize,
+                opcode: record.opcode,
+                operands: record.operands.clone(),
+            };
+            format!(
+                "{:04x} ({}): {}",
+                record.ip,
+                symbols.symbolize(record.ip),
+                format_instruction(&instr, symbols)
+            )
+        }
+        None => "end of trace".to_string(),
+    }
+}
+
+fn format_value(val: Option<u16>) -> String {
+    match val {
+        Some(val) => format!("{:04x}", val),
+        None => "----".to_string(),
+    }
+}
+
+impl Divergence {
+    /// A human readable report, using `symbols` to name addresses.
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = format!("first divergence at record {}\n", self.index);
+        out.push_str(&format!("  a: {}\n", describe(&self.a, symbols)));
+        out.push_str(&format!("  b: {}\n", describe(&self.b, symbols)));
+
+        if !self.registers.is_empty() {
+            out.push_str("registers:\n");
+            for (r, va, vb) in &self.registers {
+                out.push_str(&format!("  r{}  {:04x}  {:04x}\n", r, va, vb));
+            }
+        }
+        if !self.memory.is_empty() {
+            out.push_str("memory:\n");
+            for (addr, va, vb) in &self.memory {
+                let addr = match *addr {
+                    addr if addr >= LIMIT => format!("r{}  ", addr - LIMIT),
+                    addr => format!("{:04x}", addr),
+                };
+                out.push_str(&format!(
+                    "  {}  {}  {}\n",
+                    addr,
+                    format_value(*va),
+                    format_value(*vb)
+                ));
+            }
+        }
+
+        out.push_str("call stack:\n");
+        for (idx, (site, target)) in self.call_stack.iter().enumerate().rev() {
+            out.push_str(&format!(
+                "  #{:<2} {:04x} ({}) call {}\n",
+                self.call_stack.len() - idx,
+                site,
+                symbols.symbolize(*site),
+                symbols.symbolize(*target)
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ip: u16, opcode: Opcode, values: Vec<u16>, writes: Vec<(u16, u16)>) -> TraceRecord {
+        TraceRecord {
+            ip,
+            opcode,
+            operands: values.clone(),
+            values,
+            regs: [0; REGISTERS],
+            stack_depth: 0,
+            symbol: None,
+            writes,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = vec![
+            record(0, Opcode::Call, vec![0x10], vec![]),
+            record(0x10, Opcode::Wmem, vec![0x100, 1], vec![(0x100, 1)]),
+            record(0x13, Opcode::Set, vec![LIMIT, 2], vec![(LIMIT, 2)]),
+        ];
+        let mut b = a.clone();
+        assert_eq!(diff(&a, &b), None);
+
+        b[2] = record(0x13, Opcode::Set, vec![LIMIT, 3], vec![(LIMIT, 3)]);
+        b[2].regs[1] = 9;
+        let d = diff(&a, &b).unwrap();
+        assert_eq!(d.index, 2);
+        assert_eq!(d.registers, vec![(1, 0, 9)]);
+        assert_eq!(d.memory, vec![(LIMIT, Some(2), Some(3))]);
+        assert_eq!(d.call_stack, vec![(0, 0x10)]);
+
+        let d = diff(&a, &a[..1]).unwrap();
+        assert_eq!(d.index, 1);
+        assert_eq!(d.b, None);
+        assert!(d.registers.is_empty());
+        assert_eq!(d.memory, vec![(0x100, Some(1), None)]);
+    }
+}
@@ -0,0 +1,1234 @@
+//! The Synacor virtual machine.
+
+use crate::debugger::{Access, WatchHit, Watchpoint};
+use crate::device::{Input, Output};
+use crate::error::VmError;
+use crate::history::{Change, History};
+use crate::hooks::Hook;
+use crate::opcode::Opcode;
+use crate::patch::{Patch, PatchError, TELEPORTER};
+use crate::script::{Recorder, WALKTHROUGH};
+use crate::snapshot::Snapshot;
+use crate::symbols::SymbolTable;
+use crate::trace::Tracer;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::io;
+
+/// Addresses at or above `LIMIT` refer to registers, `LIMIT + 8` and above are invalid.
+pub const LIMIT: u16 = 32768;
+
+/// Number of general purpose registers.
+pub const REGISTERS: usize = 8;
+
+/// Why [`VM::run`] returned without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A `halt` instruction was executed.
+    Halted,
+    /// A `ret` was executed with an empty stack.
+    Returned,
+    /// An `in` instruction found the input device exhausted. `ip` is left on
+    /// the `in` so execution can resume once more input is available.
+    EndOfInput,
+    /// An `in` instruction found no queued input while pausing on input is
+    /// enabled. Feed a line with [`VM::push_input`] and run again to resume.
+    NeedsInput,
+    /// [`VM::run_for`] used up its instruction budget.
+    StepLimit,
+    /// The predicate passed to [`VM::run_until`] returned true.
+    Condition,
+    /// `ip` reached a breakpoint. Running again executes the instruction
+    /// there instead of stopping a second time.
+    Breakpoint(u16),
+    /// The instructions requested with `.step` have been executed.
+    Stepped,
+    /// An instruction touched watched memory. It has completed, so `ip` is
+    /// already on the next instruction.
+    Watchpoint(WatchHit),
+}
+
+/// Outcome of executing a single instruction with [`VM::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The instruction completed and the machine can keep going.
+    Executed,
+    /// The machine stopped instead of completing an instruction.
+    Stopped(StopReason),
+}
+
+/// A `call` that has not returned yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the `call` instruction.
+    pub call_site: u16,
+    /// Address that was called.
+    pub target: u16,
+    /// Stack length right after the return address was pushed.
+    pub depth: usize,
+}
+
+impl Frame {
+    pub fn return_addr(&self) -> u16 {
+        self.call_site + Opcode::Call.size() as u16
+    }
+}
+
+/// A Synacor machine: 15-bit address space, eight registers and an unbounded stack.
+///
+/// Registers live in `mem` directly after the address space, so `mem[LIMIT + n]`
+/// is register `n`.
+pub struct VM {
+    pub(crate) mem: Vec<u16>,
+    pub(crate) symbols: SymbolTable,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) ip: usize,
+    pub(crate) input_buffer: VecDeque<char>,
+    pub(crate) at_line_start: bool,
+    pause_on_input: bool,
+    pub(crate) input: Box<dyn Input>,
+    pub(crate) output: Box<dyn Output>,
+    pub(crate) debug: bool,
+    pub(crate) breakpoints: BTreeSet<u16>,
+    /// Breakpoint already reported at this address, so resuming executes it.
+    pub(crate) resume_from: Option<usize>,
+    /// Instructions left before stopping with [`StopReason::Stepped`].
+    pub(crate) step_budget: Option<usize>,
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    pub(crate) call_stack: Vec<Frame>,
+    /// Undo log for stepping backwards, when enabled.
+    pub(crate) history: Option<History>,
+    pub(crate) tracer: Option<Tracer>,
+    pub(crate) recorder: Option<Recorder>,
+    /// Native stand-ins for routines, by address.
+    pub(crate) hooks: BTreeMap<u16, Hook>,
+}
+
+impl fmt::Debug for VM {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("ip", &self.ip)
+            .field("stack", &self.stack)
+            .field("input_buffer", &self.input_buffer)
+            .field("debug", &self.debug)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VM {
+    /// Creates a machine with `program` loaded at address 0 and all registers
+    /// zeroed. `in` reads lines from `input` and `out` writes to `output`.
+    ///
+    /// Panics if the image does not fit in the address space, which
+    /// [`crate::loader::decode_image`] already rejects.
+    pub fn new(
+        program: &[u16],
+        symbols: &SymbolTable,
+        input: Box<dyn Input>,
+        output: Box<dyn Output>,
+    ) -> VM {
+        let size = LIMIT as usize + REGISTERS;
+        let mut mem = vec![0; size];
+        if program.len() > mem.len() {
+            panic!(
+                "Input buffer size out of bounds: {} > {}",
+                program.len(),
+                mem.len()
+            );
+        }
+        mem[0..program.len()].clone_from_slice(program);
+
+        VM {
+            mem,
+            symbols: symbols.clone(),
+            stack: vec![],
+            ip: 0,
+            input_buffer: VecDeque::new(),
+            at_line_start: true,
+            pause_on_input: false,
+            input,
+            output,
+            debug: false,
+            breakpoints: BTreeSet::new(),
+            resume_from: None,
+            step_budget: None,
+            watchpoints: vec![],
+            watch_hit: None,
+            call_stack: vec![],
+            history: None,
+            tracer: None,
+            recorder: None,
+            hooks: BTreeMap::new(),
+        }
+    }
+
+    /// The full memory image, registers included at `LIMIT..LIMIT + 8`.
+    pub fn mem(&self) -> &[u16] {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut [u16] {
+        &mut self.mem
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    /// Active calls, outermost first, tracked alongside the data stack.
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: usize) {
+        self.ip = ip;
+    }
+
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    /// Enables the per-instruction trace on stderr.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    fn reg_offset(&self, arg: u16) -> u16 {
+        if arg >= LIMIT {
+            arg - LIMIT
+        } else {
+            arg
+        }
+    }
+
+    /// Value of register `idx`. Panics if `idx` is not in `0..8`.
+    pub fn regs(&self, idx: u16) -> u16 {
+        if idx > 7 {
+            panic!("Invalid register: {}", idx);
+        }
+        self.mem[(LIMIT + idx) as usize]
+    }
+
+    /// Sets register `idx`. Panics if `idx` is not in `0..8`.
+    pub fn set_reg(&mut self, idx: u16, val: u16) {
+        if idx > 7 {
+            panic!("Invalid register: {}", idx);
+        }
+        self.mem[(LIMIT + idx) as usize] = val;
+    }
+
+    /// Address, opcode word and operands of the instruction at `ip`, for error reporting.
+    fn context(&self) -> (usize, u16, Vec<u16>) {
+        let instr = self.mem[self.ip];
+        let size = Opcode::from_u16(instr).map_or(1, |op| op.size());
+        let end = (self.ip + size).min(self.mem.len());
+        (self.ip, instr, self.mem[self.ip + 1..end].to_vec())
+    }
+
+    fn invalid_register(&self, value: u16) -> VmError {
+        let (ip, opcode, operands) = self.context();
+        VmError::InvalidRegister {
+            ip,
+            opcode,
+            operands,
+            value,
+        }
+    }
+
+    fn invalid_address(&self, addr: u16) -> VmError {
+        let (ip, opcode, operands) = self.context();
+        VmError::InvalidAddress {
+            ip,
+            opcode,
+            operands,
+            addr: addr as usize,
+        }
+    }
+
+    fn convert_arg(&self, addr: u16) -> Result<u16, VmError> {
+        if addr >= LIMIT + REGISTERS as u16 {
+            return Err(self.invalid_register(addr));
+        }
+        if addr >= LIMIT {
+            Ok(self.mem[addr as usize])
+        } else {
+            Ok(addr)
+        }
+    }
+
+    /// Fails unless `addr` is somewhere [`VM::store`] can write, for
+    /// instructions that must check before changing anything else.
+    fn check_store(&self, addr: u16) -> Result<(), VmError> {
+        if addr >= LIMIT + REGISTERS as u16 {
+            return Err(self.invalid_register(addr));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn store(&mut self, addr: u16, val: u16) -> Result<(), VmError> {
+        self.check_store(addr)?;
+        self.check_watch(addr, Access::Write, self.mem[addr as usize], val);
+        self.record(Change::Mem {
+            addr,
+            old: self.mem[addr as usize],
+        });
+        self.trace_write(addr, val);
+        self.mem[addr as usize] = val;
+        Ok(())
+    }
+
+    pub(crate) fn io_error(&self, err: io::Error) -> VmError {
+        VmError::Io {
+            ip: self.ip,
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+
+    fn read_mem(&mut self, addr: u16) -> Result<u16, VmError> {
+        if addr >= LIMIT {
+            return Err(self.invalid_address(addr));
+        }
+        let val = self.mem[addr as usize];
+        self.check_watch(addr, Access::Read, val, val);
+        Ok(val)
+    }
+
+    fn write_mem(&mut self, addr: u16, val: u16) -> Result<(), VmError> {
+        if addr >= LIMIT {
+            return Err(self.invalid_address(addr));
+        }
+        self.check_watch(addr, Access::Write, self.mem[addr as usize], val);
+        self.record(Change::Mem {
+            addr,
+            old: self.mem[addr as usize],
+        });
+        self.trace_write(addr, val);
+        self.mem[addr as usize] = val;
+        Ok(())
+    }
+
+    /// Drops frames whose return address is no longer on the stack.
+    fn trim_call_stack(&mut self) {
+        let depth = self.stack.len();
+        let keep = self.call_stack.partition_point(|f| f.depth <= depth);
+        while self.call_stack.len() > keep {
+            let frame = self.call_stack.pop().unwrap();
+            self.record(Change::FramePop(frame));
+        }
+    }
+
+    /// Records a hit if a watchpoint covers this access. The machine stops
+    /// once the current instruction has completed.
+    fn check_watch(&mut self, addr: u16, access: Access, old: u16, new: u16) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|w| w.matches(addr, access)) {
+            self.watch_hit = Some(WatchHit {
+                ip: self.ip as u16,
+                addr,
+                access,
+                old,
+                new,
+            });
+        }
+    }
+
+    fn print_op(&self, op: &str) {
+        if self.debug {
+            eprintln!(
+            "{:04x}: {:<45} 0: {:04x} 1 {:04x} 2: {:04x} 3: {:04x} 4: {:04x} 5: {:04x} 6: {:04x} 7: {:04x} s({:>2}): {:04x}",
+            self.ip, op, self.regs(0), self.regs(1), self.regs(2), self.regs(3), self.regs(4), self.regs(5), self.regs(6), self.regs(7), self.stack.len(), self.stack.last().unwrap_or(&0)
+        );
+        }
+    }
+
+    /// Captures memory, registers, stack, `ip` and queued input.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            mem: self.mem.clone(),
+            stack: self.stack.clone(),
+            ip: self.ip,
+            input_buffer: self.input_buffer.iter().collect(),
+            at_line_start: self.at_line_start,
+        }
+    }
+
+    /// Resets the machine to a state captured by [`VM::snapshot`]. Symbols,
+    /// devices and the debug flag are left as they are, and the call stack
+    /// starts over empty.
+    ///
+    /// Panics if the snapshot doesn't hold exactly memory and the registers,
+    /// which [`Snapshot::read_from`] already rejects.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        if snapshot.mem.len() != self.mem.len() {
+            panic!(
+                "Snapshot size out of bounds: {} != {}",
+                snapshot.mem.len(),
+                self.mem.len()
+            );
+        }
+        self.mem.copy_from_slice(&snapshot.mem);
+        self.stack = snapshot.stack.clone();
+        self.ip = snapshot.ip;
+        self.input_buffer = snapshot.input_buffer.chars().collect();
+        self.at_line_start = snapshot.at_line_start;
+        self.call_stack.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+    }
+
+    /// Queues a line of input for `in`, as if it had been typed. Lines
+    /// starting with `.` are debugger commands and run when `in` reaches them.
+    pub fn push_input(&mut self, input: &str) {
+        for c in input.chars() {
+            self.input_buffer.push_back(c);
+        }
+        self.input_buffer.push_back('\n');
+    }
+
+    /// When set, an `in` with nothing queued returns [`StopReason::NeedsInput`]
+    /// instead of reading from the input device.
+    pub fn set_pause_on_input(&mut self, pause: bool) {
+        self.pause_on_input = pause;
+    }
+
+    /// Removes the next queued line, without its newline.
+    fn take_line(&mut self) -> String {
+        let mut line = String::new();
+        while let Some(c) = self.input_buffer.pop_front() {
+            if c == '\n' {
+                break;
+            }
+            line.push(c);
+        }
+        line
+    }
+
+    /// Queues the full walkthrough of the game as input.
+    pub fn auto_play(&mut self) {
+        self.load_script(WALKTHROUGH);
+    }
+
+    /// Applies the built-in patch for `challenge.bin` that gets past the
+    /// teleporter confirmation, see `teleporter.patch`. Fails without
+    /// changing anything if the program is not the expected one.
+    pub fn patch(&mut self) -> Result<(), PatchError> {
+        Patch::parse(TELEPORTER)?.apply(self)
+    }
+
+    /// Executes until `halt`, or a `ret` with an empty stack. A fault in the
+    /// program stops execution with `ip` left on the faulting instruction.
+    pub fn run(&mut self) -> Result<StopReason, VmError> {
+        loop {
+            if let Step::Stopped(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Executes at most `n` instructions, returning [`StopReason::StepLimit`]
+    /// if the machine is still running afterwards.
+    pub fn run_for(&mut self, n: usize) -> Result<StopReason, VmError> {
+        for _ in 0..n {
+            if let Step::Stopped(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::StepLimit)
+    }
+
+    /// Executes until `pred` returns true, returning [`StopReason::Condition`].
+    /// The predicate is checked before every instruction, including the first.
+    pub fn run_until<F>(&mut self, mut pred: F) -> Result<StopReason, VmError>
+    where
+        F: FnMut(&VM) -> bool,
+    {
+        loop {
+            if pred(self) {
+                return Ok(StopReason::Condition);
+            }
+            if let Step::Stopped(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Executes the single instruction at `ip`.
+    pub fn step(&mut self) -> Result<Step, VmError> {
+        // the registers after memory aren't code
+        if self.ip >= LIMIT as usize {
+            return Err(VmError::IpOutOfRange { ip: self.ip });
+        }
+        if self.step_budget == Some(0) {
+            self.step_budget = None;
+            return Ok(Step::Stopped(StopReason::Stepped));
+        }
+        if self.resume_from != Some(self.ip) && self.breakpoints.contains(&(self.ip as u16)) {
+            self.resume_from = Some(self.ip);
+            return Ok(Step::Stopped(StopReason::Breakpoint(self.ip as u16)));
+        }
+        let ip = self.ip;
+        if let Some(history) = self.history.as_mut() {
+            history.discard_uncommitted();
+        }
+
+        let instr = self.mem[self.ip];
+        let opcode = match Opcode::from_u16(instr) {
+            Some(opcode) => opcode,
+            None => {
+                return Err(VmError::InvalidOpcode {
+                    ip: self.ip,
+                    opcode: instr,
+                })
+            }
+        };
+        if self.ip + opcode.size() > self.mem.len() {
+            let (ip, opcode, operands) = self.context();
+            return Err(VmError::InvalidAddress {
+                ip,
+                opcode,
+                operands,
+                addr: self.mem.len(),
+            });
+        }
+        self.trace_begin(opcode);
+
+        match opcode {
+            Opcode::Halt => {
+                // halt 0: stop execution and terminate the program
+                self.print_op("halt");
+                if let Err(err) = self.trace_end() {
+                    return Err(self.io_error(err));
+                }
+                return Ok(Step::Stopped(StopReason::Halted));
+            }
+            Opcode::Set => {
+                // set 1 a b: set register <a> to the value of <b>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let b_val = self.convert_arg(self.mem[self.ip + 2])?;
+                self.store(a, b_val)?;
+
+                self.print_op(&format!(
+                    "set  {} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    a,
+                    self.reg_offset(b),
+                    b_val
+                ));
+                self.ip += 3;
+            }
+            Opcode::Push => {
+                // push: 2 a: push <a> onto the stack
+                let a = self.mem[self.ip + 1];
+                let a_val = self.convert_arg(a)?;
+                self.stack.push(a_val);
+                self.record(Change::Push);
+
+                self.print_op(&format!(
+                    "push   {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    a_val
+                ));
+                self.ip += 2;
+            }
+            Opcode::Pop => {
+                // pop: 3 a: remove the top element from the stack and write it into <a>; empty stack = error
+                let a = self.mem[self.ip + 1];
+                self.check_store(a)?;
+                let val = match self.stack.pop() {
+                    Some(val) => val,
+                    None => {
+                        let (ip, opcode, operands) = self.context();
+                        return Err(VmError::StackUnderflow {
+                            ip,
+                            opcode,
+                            operands,
+                        });
+                    }
+                };
+                self.record(Change::Pop(val));
+                self.trim_call_stack();
+                self.store(a, val)?;
+
+                self.print_op(&format!(
+                    "pop  {} {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    a,
+                    val
+                ));
+                self.ip += 2;
+            }
+            Opcode::Eq => {
+                // eq: 4 a b c: set <a> to 1 if <b> is equal to <c>; set it to 0 otherwise
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                if b_val == c_val {
+                    self.store(a, 1)?;
+                } else {
+                    self.store(a, 0)?;
+                }
+
+                self.print_op(&format!(
+                    "eq   {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::Gt => {
+                // gt: 5 a b c: set <a> to 1 if <b> is greater than <c>; set it to 0 otherwise
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                if b_val > c_val {
+                    self.store(a, 1)?;
+                } else {
+                    self.store(a, 0)?;
+                }
+
+                self.print_op(&format!(
+                    "gt   {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::Jmp => {
+                // jmp: 6 a: jump to <a>
+                let a = self.mem[self.ip + 1];
+                let arg = self.convert_arg(a)?;
+
+                self.print_op(&format!("jmp    {:04x} ({:04x})", a, arg));
+                self.ip = arg as usize;
+            }
+            Opcode::Jt => {
+                // jt: 7 a b: if <a> is nonzero, jump to <b>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let a_val = self.convert_arg(a)?;
+                let b_val = self.convert_arg(b)?;
+
+                self.print_op(&format!(
+                    "jnz    {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    a_val,
+                    self.reg_offset(b),
+                    b_val
+                ));
+                if a_val != 0 {
+                    self.ip = b_val as usize;
+                } else {
+                    self.ip += 3;
+                }
+            }
+            Opcode::Jf => {
+                // jf: 8 a b: if <a> is zero, jump to <b>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let a_val = self.convert_arg(a)?;
+                let b_val = self.convert_arg(b)?;
+
+                self.print_op(&format!(
+                    "jz     {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    a_val,
+                    self.reg_offset(b),
+                    b_val
+                ));
+                if a_val == 0 {
+                    self.ip = b_val as usize;
+                } else {
+                    self.ip += 3;
+                }
+            }
+            Opcode::Add => {
+                // add: 9 a b c: assign into <a> the sum of <b> and <c> (modulo 32768)
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                let r = ((b_val as u32 + c_val as u32) % LIMIT as u32) as u16;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "add  {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::Mult => {
+                // mult: 10 a b c: store into <a> the product of <b> and <c> (modulo 32768)
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                let r = ((b_val as u32 * c_val as u32) % LIMIT as u32) as u16;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "mult {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::Mod => {
+                // mod: 11 a b c: store into <a> the remainder of <b> divided by <c>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                if c_val == 0 {
+                    let (ip, opcode, operands) = self.context();
+                    return Err(VmError::DivisionByZero {
+                        ip,
+                        opcode,
+                        operands,
+                    });
+                }
+                let r = b_val % c_val;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "mod  {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::And => {
+                // and: 12 a b c: stores into <a> the bitwise and of <b> and <c>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                let r = b_val & c_val;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "and  {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::Or => {
+                // or: 13 a b c: stores into <a> the bitwise or of <b> and <c>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let c = self.mem[self.ip + 3];
+                let b_val = self.convert_arg(b)?;
+                let c_val = self.convert_arg(c)?;
+
+                let r = b_val | c_val;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "or   {} {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val,
+                    self.reg_offset(c),
+                    c_val
+                ));
+                self.ip += 4;
+            }
+            Opcode::Not => {
+                // not: 14 a b: stores 15-bit bitwise inverse of <b> in <a>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let b_val = self.convert_arg(b)?;
+
+                let r = !b_val & 0b0111_1111_1111_1111;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "not  {} {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val
+                ));
+                self.ip += 3;
+            }
+            Opcode::Rmem => {
+                // rmem: 15 a b: read memory at address <b> and write it to <a>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let b_val = self.convert_arg(b)?;
+
+                let r = self.read_mem(b_val)?;
+                self.store(a, r)?;
+
+                self.print_op(&format!(
+                    "rmem {} {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    self.reg_offset(b),
+                    b_val
+                ));
+                self.ip += 3;
+            }
+            Opcode::Wmem => {
+                // wmem: 16 a b: write the value from <b> into memory at address <a>
+                let a = self.mem[self.ip + 1];
+                let b = self.mem[self.ip + 2];
+                let a_val = self.convert_arg(a)?;
+                let b_val = self.convert_arg(b)?;
+
+                self.write_mem(a_val, b_val)?;
+
+                self.print_op(&format!(
+                    "wmem {:04x} ({:04x}) {:04x} ({:04x})",
+                    self.reg_offset(a),
+                    a_val,
+                    self.reg_offset(b),
+                    b_val
+                ));
+                self.ip += 3;
+            }
+            Opcode::Call => {
+                // call: 17 a: write the address of the next instruction to the stack and jump to <a>
+                let a = self.mem[self.ip + 1];
+                let a_val = self.convert_arg(a)?;
+                if self.run_hook(a_val)? {
+                    // a native hook ran instead, as if the routine had returned
+                    self.print_op(&format!(
+                        "call {:04x} ({:04x}) hooked",
+                        self.reg_offset(a),
+                        a_val
+                    ));
+                    self.ip += 2;
+                } else {
+                    self.stack.push((self.ip + 2) as u16);
+                    self.call_stack.push(Frame {
+                        call_site: self.ip as u16,
+                        target: a_val,
+                        depth: self.stack.len(),
+                    });
+                    self.record(Change::Push);
+                    self.record(Change::FramePush);
+
+                    let symbol = self.symbols.get(a_val);
+
+                    if let Some(sym) = symbol {
+                        self.print_op(&format!(
+                            "call {} {:04x} ({:04x})",
+                            sym,
+                            self.reg_offset(a),
+                            a_val
+                        ));
+                    } else {
+                        self.print_op(&format!("call {:04x} ({:04x})", self.reg_offset(a), a_val));
+                    }
+
+                    if self.debug {
+                        eprintln!();
+                        if let Some(sym) = symbol {
+                            eprintln!("{}:", sym);
+                        }
+                    }
+                    self.ip = a_val as usize;
+                }
+            }
+            Opcode::Ret => {
+                // ret: 18: remove the top element from the stack and jump to it; empty stack = halt
+                if self.stack.is_empty() {
+                    if let Err(err) = self.trace_end() {
+                        return Err(self.io_error(err));
+                    }
+                    return Ok(Step::Stopped(StopReason::Returned));
+                }
+                let val = self.stack.pop().unwrap();
+                self.record(Change::Pop(val));
+                self.trim_call_stack();
+
+                self.print_op(&format!("ret  {:04x}", val));
+                if self.debug {
+                    eprintln!();
+                }
+                self.ip = val as usize;
+            }
+            Opcode::Out => {
+                // out: 19 a: write the character represented by ascii code <a> to the terminal
+                let a = self.mem[self.ip + 1];
+                let a_val = self.convert_arg(a)?;
+                let val = a_val as u8 as char;
+                if let Err(err) = self.output.write_str(val.encode_utf8(&mut [0; 4])) {
+                    return Err(self.io_error(err));
+                }
+
+                let mut debug_val: &str = &val.to_string();
+                if val == '\n' {
+                    debug_val = "\\n";
+                    /*self.debug = !self.debug;
+                    self.print_op("dbg");
+                    self.debug = !self.debug;*/
+                }
+                self.print_op(&format!(
+                    "out    {:04x} ({})",
+                    self.reg_offset(a),
+                    debug_val
+                ));
+                self.ip += 2;
+            }
+            Opcode::In => {
+                // in: 20 a: read a character from the terminal and write its ascii code to <a>;
+                // it can be assumed that once input starts, it will continue until a newline
+                // is encountered;
+                // this means that you can safely read whole lines from the keyboard
+                // and trust that they will be fully read
+                loop {
+                    if self.input_buffer.is_empty() {
+                        if self.pause_on_input {
+                            return Ok(Step::Stopped(StopReason::NeedsInput));
+                        }
+
+                        let line = self.output.flush().and_then(|_| self.input.read_line());
+                        match line {
+                            Ok(Some(input)) => self.push_input(&input),
+                            Ok(None) => return Ok(Step::Stopped(StopReason::EndOfInput)),
+                            Err(err) => return Err(self.io_error(err)),
+                        }
+                    } else if self.at_line_start && self.input_buffer[0] == '.' {
+                        let line = self.take_line();
+                        if let Err(err) = self.record_debug(&line) {
+                            return Err(self.io_error(err));
+                        }
+                        self.handle_debug(&line)?;
+                        // a .load or .rstep may have moved execution away from this instruction
+                        if self.ip != ip || self.mem[self.ip] != Opcode::In.code() {
+                            return Ok(Step::Executed);
+                        }
+                    } else {
+                        break;
+                    }
+                }
+
+                let a = self.mem[self.ip + 1];
+                self.check_store(a)?;
+                let val = self.input_buffer.pop_front().unwrap();
+                self.record(Change::Input {
+                    c: val,
+                    at_line_start: self.at_line_start,
+                });
+                self.at_line_start = val == '\n';
+                if let Err(err) = self.record_input(val) {
+                    return Err(self.io_error(err));
+                }
+                let r = val as u16;
+                self.store(a, r)?;
+
+                let mut debug_val: &str = &val.to_string();
+                if val == '\n' {
+                    debug_val = "\\n";
+                }
+                self.print_op(&format!(
+                    "in     {:04x} {:04x} ({})",
+                    self.reg_offset(a),
+                    r,
+                    debug_val
+                ));
+
+                self.ip += 2;
+            }
+            Opcode::Noop => {
+                // noop: 21: no operation
+                self.print_op("noop");
+                self.ip += 1;
+            }
+        }
+
+        self.resume_from = None;
+        if let Some(history) = self.history.as_mut() {
+            history.commit(ip);
+        }
+        if let Err(err) = self.trace_end() {
+            return Err(self.io_error(err));
+        }
+        if let Some(n) = self.step_budget.as_mut() {
+            *n -= 1;
+        }
+        if let Some(hit) = self.watch_hit.take() {
+            return Ok(Step::Stopped(StopReason::Watchpoint(hit)));
+        }
+        Ok(Step::Executed)
+    }
+}
+
+/// A machine for tests with `program` loaded, reading `input` and with its
+/// output captured.
+#[cfg(test)]
+pub(crate) fn test_vm(program: &[u16], input: &str) -> (VM, crate::device::BufferOutput) {
+    test_vm_with_symbols(program, &SymbolTable::new(), input)
+}
+
+/// Like [`test_vm`], with names from `symbols`.
+#[cfg(test)]
+pub(crate) fn test_vm_with_symbols(
+    program: &[u16],
+    symbols: &SymbolTable,
+    input: &str,
+) -> (VM, crate::device::BufferOutput) {
+    use crate::device::{BufferInput, BufferOutput};
+
+    let output = BufferOutput::new();
+    let vm = VM::new(
+        program,
+        symbols,
+        Box::new(BufferInput::new(input)),
+        Box::new(output.clone()),
+    );
+    (vm, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simple() {
+        // - The program "9,32768,32769,4,19,32768" occupies six memory addresses and should:
+        //  - Store into register 0 the sum of 4 and the value contained in register 1.
+        //  - Output to the terminal the character with the ascii code contained in register 0.
+
+        let program = vec![9, 32768, 32769, 4, 19, 32768];
+        let (mut vm, output) = test_vm(&program, "");
+        vm.debug = true;
+        assert_eq!(vm.run(), Ok(StopReason::Halted));
+
+        assert_eq!(vm.regs(0), 4);
+        assert_eq!(vm.ip, 6);
+        assert_eq!(output.contents(), "\u{4}");
+    }
+
+    #[test]
+    fn test_step() {
+        // add r0 r0 1; jmp 0
+        let program = vec![9, 32768, 32768, 1, 6, 0];
+        let (mut vm, _) = test_vm(&program, "");
+
+        assert_eq!(vm.step(), Ok(Step::Executed));
+        assert_eq!(vm.ip, 4);
+        assert_eq!(vm.run_for(5), Ok(StopReason::StepLimit));
+        assert_eq!(vm.regs(0), 3);
+        assert_eq!(
+            vm.run_until(|vm| vm.regs(0) == 10),
+            Ok(StopReason::Condition)
+        );
+        assert_eq!(vm.ip, 4);
+
+        let (mut vm, _) = test_vm(&[21, 0], "");
+        assert_eq!(vm.run_for(5), Ok(StopReason::Halted));
+        assert_eq!(vm.step(), Ok(Step::Stopped(StopReason::Halted)));
+    }
+
+    #[test]
+    fn test_needs_input() {
+        let program = vec![20, 32768, 19, 32768, 6, 0];
+        let (mut vm, output) = test_vm(&program, "never read");
+        vm.set_pause_on_input(true);
+
+        assert_eq!(vm.run(), Ok(StopReason::NeedsInput));
+        vm.push_input("ok");
+        assert_eq!(vm.run(), Ok(StopReason::NeedsInput));
+        assert_eq!(output.contents(), "ok\n");
+
+        vm.push_input(".wreg 2 7");
+        vm.push_input("a.b");
+        assert_eq!(vm.run(), Ok(StopReason::NeedsInput));
+        assert_eq!(vm.regs(2), 7);
+        assert_eq!(output.contents(), "ok\nDEBUG: wreg 2 7\n\na.b\n");
+        assert_eq!(vm.ip, 0);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // add r0 r0 1; jmp 0
+        let program = vec![9, 32768, 32768, 1, 6, 0];
+        let (mut vm, _) = test_vm(&program, "");
+        vm.add_breakpoint(4);
+
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint(4)));
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint(4)));
+        assert_eq!(vm.regs(0), 2);
+
+        vm.remove_breakpoint(4);
+        vm.step_budget = Some(3);
+        assert_eq!(vm.run(), Ok(StopReason::Stepped));
+        assert_eq!(vm.ip, 0);
+        assert_eq!(vm.regs(0), 3);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // wmem 0x10 5; rmem r0 0x10; set r1 0x10; halt
+        let program = vec![16, 0x10, 5, 15, 32768, 0x10, 1, 32769, 0x10, 0];
+        let (mut vm, _) = test_vm(&program, "");
+        vm.add_watchpoint(Watchpoint::new(0x10, 0x10, Access::ReadWrite));
+
+        let hit = WatchHit {
+            ip: 0,
+            addr: 0x10,
+            access: Access::Write,
+            old: 0,
+            new: 5,
+        };
+        assert_eq!(vm.run(), Ok(StopReason::Watchpoint(hit)));
+        assert_eq!(vm.ip, 3);
+
+        let hit = WatchHit {
+            ip: 3,
+            addr: 0x10,
+            access: Access::Read,
+            old: 5,
+            new: 5,
+        };
+        assert_eq!(vm.run(), Ok(StopReason::Watchpoint(hit)));
+        assert_eq!(vm.run(), Ok(StopReason::Halted));
+    }
+
+    #[test]
+    fn test_call_stack() {
+        // call 0x0004; halt; push 1; call 0x000b; pop r0; ret; in r0; ret
+        let program = vec![
+            17, 0x0004, 0, 0, 2, 1, 17, 0x000b, 3, 32768, 18, 20, 32768, 18,
+        ];
+        let (mut vm, _) = test_vm(&program, "");
+        vm.add_breakpoint(0x000b);
+
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint(0x000b)));
+        let targets: Vec<u16> = vm.call_stack().iter().map(|f| f.target).collect();
+        assert_eq!(targets, vec![0x0004, 0x000b]);
+        assert_eq!(vm.call_stack()[1].return_addr(), 0x0008);
+
+        vm.push_input("");
+        vm.add_breakpoint(0x0002);
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint(0x0002)));
+        assert!(vm.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let program = vec![20, 32768, 19, 32768, 6, 0];
+        let (mut vm, output) = test_vm(&program, "");
+        vm.set_pause_on_input(true);
+        vm.push_input("ab");
+        vm.run_for(2).unwrap();
+
+        let snapshot = vm.snapshot();
+        assert_eq!(snapshot.input_buffer, "b\n");
+        vm.run().unwrap();
+        assert_eq!(output.contents(), "ab\n");
+
+        vm.restore(&snapshot);
+        vm.run().unwrap();
+        assert_eq!(output.contents(), "ab\nb\n");
+    }
+
+    #[test]
+    fn test_devices() {
+        // echo every character read until the input runs out
+        let program = vec![20, 32768, 19, 32768, 6, 0];
+        let (mut vm, output) = test_vm(&program, "hi\n.wreg 1 5\nyo");
+
+        assert_eq!(vm.run(), Ok(StopReason::EndOfInput));
+        assert_eq!(output.contents(), "hi\nDEBUG: wreg 1 5\n\nyo\n");
+        assert_eq!(vm.regs(1), 5);
+        assert_eq!(vm.ip, 0);
+    }
+
+    #[test]
+    fn test_faults() {
+        let (mut vm, _) = test_vm(&[3, 32768], "");
+        assert_eq!(
+            vm.run(),
+            Err(VmError::StackUnderflow {
+                ip: 0,
+                opcode: 3,
+                operands: vec![32768]
+            })
+        );
+
+        let (mut vm, _) = test_vm(&[11, 32768, 5, 0], "");
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::DivisionByZero { ip: 0, .. })
+        ));
+
+        let (mut vm, _) = test_vm(&[21, 1, 32776, 1], "");
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::InvalidRegister {
+                ip: 1,
+                value: 32776,
+                ..
+            })
+        ));
+
+        let (mut vm, _) = test_vm(&[21, 42], "");
+        assert_eq!(vm.run(), Err(VmError::InvalidOpcode { ip: 1, opcode: 42 }));
+
+        // a fault leaves the stack and input as they were
+        let (mut vm, _) = test_vm(&[2, 5, 3, 40000], "");
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::InvalidRegister { ip: 2, .. })
+        ));
+        assert_eq!(vm.stack(), &[5]);
+        let (mut vm, _) = test_vm(&[20, 40000], "x");
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::InvalidRegister { ip: 0, .. })
+        ));
+        vm.mem_mut()[1] = 32768;
+        assert_eq!(vm.run_for(1), Ok(StopReason::StepLimit));
+        assert_eq!(vm.regs(0), 'x' as u16);
+
+        // the registers can't be run as code: rmem r0 0x0005; jmp r0
+        let (mut vm, _) = test_vm(&[15, 32768, 5, 6, 32768, 0x8000], "");
+        assert_eq!(vm.run(), Err(VmError::IpOutOfRange { ip: 0x8000 }));
+    }
+}